@@ -6,8 +6,12 @@ use clap::{crate_version, App, Arg};
 use tikv_client::TransactionClient;
 use tracing_subscriber::EnvFilter;
 
+use tifs::fs::error::FsError;
 use tifs::fs::inode::Inode;
+use tifs::fs::intent::IntentKind;
 use tifs::fs::key::{ScopedKey, ROOT_INODE};
+use tifs::fs::quota::QuotaKind;
+use tifs::fs::tikv_fs::TiFs;
 use tifs::fs::transaction::Txn;
 
 #[async_std::main]
@@ -71,7 +75,11 @@ impl Console {
         let mut txn = Txn::begin_optimistic(&self.client).await?;
         match self.interact_with_txn(&mut txn).await {
             Ok(exit) => {
+                let intents = txn.take_intents();
                 txn.commit().await?;
+                for ino in intents {
+                    self.complete_intent(ino).await?;
+                }
                 Ok(exit)
             }
             Err(err) => {
@@ -81,6 +89,38 @@ impl Console {
         }
     }
 
+    /// Apply steps of the intent of an inode in separate transactions until it is done,
+    /// along with intents set by the steps and pending intents they run into.
+    async fn complete_intent(&self, ino: u64) -> Result<()> {
+        let mut pending = vec![ino];
+        while let Some(&ino) = pending.last() {
+            let mut txn = Txn::begin_optimistic(&self.client).await?;
+            match txn.step_intent(ino).await {
+                Ok(done) => {
+                    let intents = txn.take_intents();
+                    txn.commit().await?;
+                    if done {
+                        pending.pop();
+                    }
+                    for ino in intents {
+                        if !pending.contains(&ino) {
+                            pending.push(ino);
+                        }
+                    }
+                }
+                Err(FsError::PendingIntent { ino }) if !pending.contains(&ino) => {
+                    txn.rollback().await?;
+                    pending.push(ino);
+                }
+                Err(err) => {
+                    txn.rollback().await?;
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
     async fn interact_with_txn(&self, txn: &mut Txn) -> Result<bool> {
         print!("{:?}> ", &self.pd_endpoints);
         stdout().flush()?;
@@ -101,6 +141,18 @@ impl Console {
             "get_raw" => self.get_attr_raw(txn, &commands[1..]).await?,
             "get_inline" => self.get_inline(txn, &commands[1..]).await?,
             "rm" => self.delete_block(txn, &commands[1..]).await?,
+            "snapshot" => self.create_snapshot(txn, &commands[1..]).await?,
+            "snapshots" => self.list_snapshots(txn).await?,
+            "restore" => self.restore_snapshot(txn, &commands[1..]).await?,
+            "rm_snapshot" => self.delete_snapshot(txn, &commands[1..]).await?,
+            "quota" => self.set_quota(txn, &commands[1..]).await?,
+            "quotas" => self.list_quotas(txn).await?,
+            "rm_quota" => self.remove_quota(txn, &commands[1..]).await?,
+            "project" => self.set_project(txn, &commands[1..]).await?,
+            "capacity" => self.set_capacity(txn, &commands[1..]).await?,
+            "usage" => self.read_usage(txn).await?,
+            "recount" => self.recount_usage(txn).await?,
+            "sessions" => self.list_sessions(txn).await?,
             cmd => return Err(anyhow!("unknow command `{}`", cmd)),
         }
 
@@ -113,6 +165,10 @@ impl Console {
             .await?
             .map(|meta| meta.inode_next)
             .unwrap_or(ROOT_INODE);
+        // every session is taken as expired, as the volume is cleared anyway
+        for session in txn.list_sessions().await? {
+            while !txn.reap_session(session.id, std::u64::MAX).await? {}
+        }
         for inode in txn
             .scan(
                 ScopedKey::inode_range(ROOT_INODE..next_inode),
@@ -123,12 +179,168 @@ impl Console {
         {
             let inode = inode?;
             txn.clear_data(inode.ino).await?;
+            match &inode.intent {
+                // walking a tree would copy it again, so its queue is dropped instead
+                Some(intent) if matches!(intent.kind, IntentKind::Clone | IntentKind::Empty) => {
+                    self.clear_tree(txn, inode.ino).await?
+                }
+                _ => while !txn.step_intent(inode.ino).await? {},
+            }
             txn.remove_inode(inode.ino).await?;
         }
+        for snapshot in txn.list_snapshots().await? {
+            txn.delete(ScopedKey::snapshot(&snapshot.name)).await?;
+        }
+        for quota in txn.list_quotas().await? {
+            txn.remove_quota(quota.kind, quota.id).await?;
+        }
+        for shard in 0..TiFs::USAGE_SHARDS {
+            txn.delete(ScopedKey::usage(shard)).await?;
+        }
         txn.delete(ScopedKey::meta()).await?;
         Ok(())
     }
 
+    /// Delete the queue of a tree walked by the intent of `root`.
+    async fn clear_tree(&self, txn: &mut Txn, root: u64) -> Result<()> {
+        let ranges = vec![
+            ScopedKey::tree_task_range(root),
+            ScopedKey::tree_fork_range(root),
+        ];
+        for range in ranges {
+            let keys: Vec<_> = txn.scan_keys(range, std::u32::MAX).await?.collect();
+            for key in keys {
+                txn.delete(key).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn resolve(&self, txn: &mut Txn, path: &str) -> Result<u64> {
+        let mut ino = ROOT_INODE;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            ino = txn.lookup(ino, name.to_owned().into()).await?;
+        }
+        Ok(ino)
+    }
+
+    async fn create_snapshot(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        if args.len() < 1 {
+            return Err(anyhow!("invalid arguments `{:?}`", args));
+        }
+        let source = self.resolve(txn, args.get(1).unwrap_or(&"/")).await?;
+        let snapshot = txn
+            .create_snapshot(args[0].to_owned().into(), source)
+            .await?;
+        println!("{:?}", snapshot);
+        Ok(())
+    }
+
+    async fn list_snapshots(&self, txn: &mut Txn) -> Result<()> {
+        for snapshot in txn.list_snapshots().await? {
+            println!("{:?}", snapshot);
+        }
+        Ok(())
+    }
+
+    async fn restore_snapshot(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        if args.len() < 1 {
+            return Err(anyhow!("invalid arguments `{:?}`", args));
+        }
+        let snapshot = txn.restore_snapshot(args[0].to_owned().into()).await?;
+        println!("{:?}", snapshot);
+        Ok(())
+    }
+
+    async fn delete_snapshot(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        if args.len() < 1 {
+            return Err(anyhow!("invalid arguments `{:?}`", args));
+        }
+        txn.delete_snapshot(args[0].to_owned().into()).await?;
+        Ok(())
+    }
+
+    fn parse_quota_kind(kind: &str) -> Result<QuotaKind> {
+        match kind {
+            "user" => Ok(QuotaKind::User),
+            "group" => Ok(QuotaKind::Group),
+            "project" => Ok(QuotaKind::Project),
+            _ => Err(anyhow!("unknown quota kind `{}`", kind)),
+        }
+    }
+
+    fn parse_limit(limit: Option<&&str>) -> Result<Option<u64>> {
+        match limit {
+            None | Some(&"-") => Ok(None),
+            Some(limit) => Ok(Some(limit.parse()?)),
+        }
+    }
+
+    async fn set_quota(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        if args.len() < 2 {
+            return Err(anyhow!("invalid arguments `{:?}`", args));
+        }
+        let quota = txn
+            .set_quota(
+                Self::parse_quota_kind(args[0])?,
+                args[1].parse()?,
+                Self::parse_limit(args.get(2))?,
+                Self::parse_limit(args.get(3))?,
+            )
+            .await?;
+        println!("{:?}", quota);
+        Ok(())
+    }
+
+    async fn list_quotas(&self, txn: &mut Txn) -> Result<()> {
+        for quota in txn.list_quotas().await? {
+            println!("{:?}", quota);
+        }
+        Ok(())
+    }
+
+    async fn remove_quota(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        if args.len() < 2 {
+            return Err(anyhow!("invalid arguments `{:?}`", args));
+        }
+        txn.remove_quota(Self::parse_quota_kind(args[0])?, args[1].parse()?)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_project(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        if args.len() < 2 {
+            return Err(anyhow!("invalid arguments `{:?}`", args));
+        }
+        let root = self.resolve(txn, args[0]).await?;
+        txn.set_project(root, args[1].parse()?).await?;
+        Ok(())
+    }
+
+    async fn set_capacity(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        let meta = txn.set_capacity(Self::parse_limit(args.get(0))?).await?;
+        println!("{:?}", meta);
+        Ok(())
+    }
+
+    async fn read_usage(&self, txn: &mut Txn) -> Result<()> {
+        println!("{:?}", txn.read_usage().await?);
+        Ok(())
+    }
+
+    async fn recount_usage(&self, txn: &mut Txn) -> Result<()> {
+        println!("{:?}", txn.recount_usage().await?);
+        Ok(())
+    }
+
+    async fn list_sessions(&self, txn: &mut Txn) -> Result<()> {
+        let now = Txn::clock(&self.client).await?;
+        for session in txn.list_sessions().await? {
+            println!("{:?}, expired: {}", session, session.expired(now));
+        }
+        Ok(())
+    }
+
     async fn get_block(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
         if args.len() < 2 {
             return Err(anyhow!("invalid arguments `{:?}`", args));
@@ -161,10 +373,7 @@ impl Console {
         if args.len() < 1 {
             return Err(anyhow!("invalid arguments `{:?}`", args));
         }
-        match txn.get(ScopedKey::inode(args[0].parse()?)).await? {
-            Some(value) => println!("{:?}", Inode::deserialize(&value)?),
-            None => println!("Not Found"),
-        }
+        println!("{:?}", txn.read_inode_unchecked(args[0].parse()?).await?);
         Ok(())
     }
 
@@ -183,13 +392,8 @@ impl Console {
         if args.len() < 1 {
             return Err(anyhow!("invalid arguments `{:?}`", args));
         }
-        match txn.get(ScopedKey::inode(args[0].parse()?)).await? {
-            Some(value) => {
-                let inline = Inode::deserialize(&value)?
-                    .inline_data
-                    .unwrap_or_else(Vec::new);
-                println!("{}", String::from_utf8_lossy(&inline));
-            }
+        match txn.get(ScopedKey::inline(args[0].parse()?)).await? {
+            Some(inline) => println!("{}", String::from_utf8_lossy(&inline)),
             None => println!("Not Found"),
         }
         Ok(())
@@ -1,14 +1,22 @@
+pub mod acl;
 pub mod async_fs;
 pub mod block;
+pub mod credentials;
 pub mod dir;
 pub mod error;
 pub mod file_handler;
 pub mod index;
 pub mod inode;
+pub mod intent;
 pub mod key;
+pub mod lock;
 pub mod meta;
 pub mod mode;
+pub mod quota;
 pub mod reply;
 pub mod serialize;
+pub mod session;
+pub mod snapshot;
 pub mod tikv_fs;
 pub mod transaction;
+pub mod usage;
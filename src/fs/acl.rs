@@ -0,0 +1,409 @@
+use std::convert::TryInto;
+
+use fuser::{FileAttr, FileType};
+
+use super::credentials::Credentials;
+use super::error::{FsError, Result};
+
+pub const ACL_ACCESS: &str = "system.posix_acl_access";
+pub const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+const ACL_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = std::u32::MAX;
+const HEADER_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 8;
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
+pub enum AclTag {
+    UserObj,
+    User,
+    GroupObj,
+    Group,
+    Mask,
+    Other,
+}
+
+impl AclTag {
+    const fn from_raw(tag: u16) -> Option<Self> {
+        use AclTag::*;
+
+        match tag {
+            0x01 => Some(UserObj),
+            0x02 => Some(User),
+            0x04 => Some(GroupObj),
+            0x08 => Some(Group),
+            0x10 => Some(Mask),
+            0x20 => Some(Other),
+            _ => None,
+        }
+    }
+
+    const fn raw(self) -> u16 {
+        use AclTag::*;
+
+        match self {
+            UserObj => 0x01,
+            User => 0x02,
+            GroupObj => 0x04,
+            Group => 0x08,
+            Mask => 0x10,
+            Other => 0x20,
+        }
+    }
+}
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    pub perm: u16,
+    pub id: u32,
+}
+
+/// A POSIX access control list, encoded in the xattr format of linux.
+///
+/// Entries are kept sorted by tag and id, as the kernel requires.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    /// The minimal acl equivalent to permission bits.
+    pub fn from_mode(perm: u16) -> Self {
+        let entry = |tag, shift: u16| AclEntry {
+            tag,
+            perm: (perm >> shift) & 0o7,
+            id: ACL_UNDEFINED_ID,
+        };
+        Self {
+            entries: vec![
+                entry(AclTag::UserObj, 6),
+                entry(AclTag::GroupObj, 3),
+                entry(AclTag::Other, 0),
+            ],
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let invalid_acl = || FsError::InvalidAcl;
+        if data.len() < HEADER_SIZE || (data.len() - HEADER_SIZE) % ENTRY_SIZE != 0 {
+            return Err(invalid_acl());
+        }
+        if u32::from_le_bytes(data[..HEADER_SIZE].try_into().unwrap()) != ACL_VERSION {
+            return Err(invalid_acl());
+        }
+
+        let mut entries = Vec::with_capacity((data.len() - HEADER_SIZE) / ENTRY_SIZE);
+        for chunk in data[HEADER_SIZE..].chunks(ENTRY_SIZE) {
+            let tag = AclTag::from_raw(u16::from_le_bytes(chunk[..2].try_into().unwrap()))
+                .ok_or_else(invalid_acl)?;
+            let perm = u16::from_le_bytes(chunk[2..4].try_into().unwrap());
+            let id = match tag {
+                AclTag::User | AclTag::Group => u32::from_le_bytes(chunk[4..].try_into().unwrap()),
+                _ => ACL_UNDEFINED_ID,
+            };
+            if perm & !0o7 != 0 {
+                return Err(invalid_acl());
+            }
+            entries.push(AclEntry { tag, perm, id });
+        }
+
+        entries.sort_by_key(|entry| (entry.tag, entry.id));
+        // an acl naming a user or group twice is invalid, like the kernel checks
+        if entries
+            .windows(2)
+            .any(|pair| pair[0].tag == pair[1].tag && pair[0].id == pair[1].id)
+        {
+            return Err(invalid_acl());
+        }
+        let acl = Self { entries };
+        if !acl.valid() {
+            return Err(invalid_acl());
+        }
+        Ok(acl)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + self.entries.len() * ENTRY_SIZE);
+        data.extend(ACL_VERSION.to_le_bytes().iter());
+        for entry in &self.entries {
+            data.extend(entry.tag.raw().to_le_bytes().iter());
+            data.extend(entry.perm.to_le_bytes().iter());
+            data.extend(entry.id.to_le_bytes().iter());
+        }
+        data
+    }
+
+    fn count(&self, tag: AclTag) -> usize {
+        self.entries.iter().filter(|entry| entry.tag == tag).count()
+    }
+
+    fn entry_mut(&mut self, tag: AclTag) -> Option<&mut AclEntry> {
+        self.entries.iter_mut().find(|entry| entry.tag == tag)
+    }
+
+    fn perm_of(&self, tag: AclTag) -> u16 {
+        self.entries
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| entry.perm)
+            .unwrap_or(0)
+    }
+
+    /// Exactly one entry of each owner class is required,
+    /// while a mask is required only if there are named entries.
+    fn valid(&self) -> bool {
+        let named = self.count(AclTag::User) + self.count(AclTag::Group);
+        self.count(AclTag::UserObj) == 1
+            && self.count(AclTag::GroupObj) == 1
+            && self.count(AclTag::Other) == 1
+            && self.count(AclTag::Mask) <= 1
+            && (named == 0 || self.count(AclTag::Mask) == 1)
+    }
+
+    /// Whether the acl can be represented by permission bits only.
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    /// Permission bits of the file, the group class is represented by the mask if there is one.
+    pub fn mode(&self) -> u16 {
+        let group = if self.count(AclTag::Mask) == 1 {
+            self.perm_of(AclTag::Mask)
+        } else {
+            self.perm_of(AclTag::GroupObj)
+        };
+        (self.perm_of(AclTag::UserObj) << 6) | (group << 3) | self.perm_of(AclTag::Other)
+    }
+
+    /// Update the acl after a chmod.
+    pub fn set_mode(&mut self, perm: u16) {
+        let group_tag = if self.count(AclTag::Mask) == 1 {
+            AclTag::Mask
+        } else {
+            AclTag::GroupObj
+        };
+        for (tag, shift) in [(AclTag::UserObj, 6), (group_tag, 3), (AclTag::Other, 0)].iter() {
+            if let Some(entry) = self.entry_mut(*tag) {
+                entry.perm = (perm >> shift) & 0o7;
+            }
+        }
+    }
+
+    /// Derive the access acl of a new file from the default acl of its parent,
+    /// the permission bits of the owner classes are limited by `perm`.
+    pub fn inherit(&self, perm: u16) -> Self {
+        let mut acl = self.clone();
+        let current = acl.mode();
+        acl.set_mode(current & perm);
+        acl
+    }
+
+    /// Check if the acl grants all of the `mask`(in `rwx` bits) to the caller.
+    pub fn permits(&self, attr: &FileAttr, cred: &Credentials, mask: u16) -> bool {
+        if cred.is_root() {
+            return mask & 0o1 == 0 || attr.kind == FileType::Directory || self.mode() & 0o111 != 0;
+        }
+
+        let granted = |perm: u16| perm & mask == mask;
+        let class_mask = if self.count(AclTag::Mask) == 1 {
+            self.perm_of(AclTag::Mask)
+        } else {
+            0o7
+        };
+
+        if cred.uid == attr.uid {
+            return granted(self.perm_of(AclTag::UserObj));
+        }
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|entry| entry.tag == AclTag::User && entry.id == cred.uid)
+        {
+            return granted(entry.perm & class_mask);
+        }
+
+        let matched: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|entry| match entry.tag {
+                AclTag::GroupObj => cred.in_group(attr.gid),
+                AclTag::Group => cred.in_group(entry.id),
+                _ => false,
+            })
+            .collect();
+        if !matched.is_empty() {
+            return matched.iter().any(|entry| granted(entry.perm & class_mask));
+        }
+
+        granted(self.perm_of(AclTag::Other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    fn raw(entries: &[(AclTag, u16, u32)]) -> Vec<u8> {
+        let mut data = ACL_VERSION.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            data.extend(tag.raw().to_le_bytes().iter());
+            data.extend(perm.to_le_bytes().iter());
+            data.extend(id.to_le_bytes().iter());
+        }
+        data
+    }
+
+    fn attr(uid: u32, gid: u32) -> FileAttr {
+        FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o640,
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            blksize: 4096,
+            padding: 0,
+            flags: 0,
+        }
+    }
+
+    // supplementary groups are left unresolved
+    fn cred(uid: u32, gid: u32) -> Credentials {
+        Credentials::new(uid, gid, 0)
+    }
+
+    fn named() -> Acl {
+        Acl::parse(&raw(&[
+            (AclTag::UserObj, 0o6, ACL_UNDEFINED_ID),
+            (AclTag::User, 0o7, 1001),
+            (AclTag::GroupObj, 0o4, ACL_UNDEFINED_ID),
+            (AclTag::Group, 0o6, 2001),
+            (AclTag::Mask, 0o6, ACL_UNDEFINED_ID),
+            (AclTag::Other, 0o0, ACL_UNDEFINED_ID),
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_and_encode() {
+        let data = raw(&[
+            (AclTag::UserObj, 0o6, ACL_UNDEFINED_ID),
+            (AclTag::GroupObj, 0o4, ACL_UNDEFINED_ID),
+            (AclTag::Other, 0o4, ACL_UNDEFINED_ID),
+        ]);
+        let acl = Acl::parse(&data).unwrap();
+        assert!(acl.is_minimal());
+        assert_eq!(acl.mode(), 0o644);
+        assert_eq!(acl.encode(), data);
+        assert_eq!(Acl::from_mode(0o644), acl);
+    }
+
+    #[test]
+    fn parse_sorts_entries() {
+        let acl = Acl::parse(&raw(&[
+            (AclTag::Other, 0o0, ACL_UNDEFINED_ID),
+            (AclTag::Mask, 0o6, ACL_UNDEFINED_ID),
+            (AclTag::Group, 0o6, 2001),
+            (AclTag::GroupObj, 0o4, ACL_UNDEFINED_ID),
+            (AclTag::User, 0o7, 1001),
+            (AclTag::UserObj, 0o6, ACL_UNDEFINED_ID),
+        ]))
+        .unwrap();
+        assert_eq!(acl, named());
+        assert!(!acl.is_minimal());
+        assert_eq!(acl.mode(), 0o660);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let minimal = raw(&[
+            (AclTag::UserObj, 0o6, ACL_UNDEFINED_ID),
+            (AclTag::GroupObj, 0o4, ACL_UNDEFINED_ID),
+            (AclTag::Other, 0o4, ACL_UNDEFINED_ID),
+        ]);
+        assert!(Acl::parse(&minimal[..minimal.len() - 1]).is_err());
+        let mut version = minimal.clone();
+        version[0] = 1;
+        assert!(Acl::parse(&version).is_err());
+        // a named entry requires a mask
+        assert!(Acl::parse(&raw(&[
+            (AclTag::UserObj, 0o6, ACL_UNDEFINED_ID),
+            (AclTag::User, 0o6, 1001),
+            (AclTag::GroupObj, 0o4, ACL_UNDEFINED_ID),
+            (AclTag::Other, 0o4, ACL_UNDEFINED_ID),
+        ]))
+        .is_err());
+        assert!(Acl::parse(&raw(&[
+            (AclTag::UserObj, 0o10, ACL_UNDEFINED_ID),
+            (AclTag::GroupObj, 0o4, ACL_UNDEFINED_ID),
+            (AclTag::Other, 0o4, ACL_UNDEFINED_ID),
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn reject_duplicates() {
+        // the duplicates would not be adjacent if entries were sorted by perm before id
+        assert!(Acl::parse(&raw(&[
+            (AclTag::UserObj, 0o6, ACL_UNDEFINED_ID),
+            (AclTag::User, 0o4, 1001),
+            (AclTag::User, 0o5, 1002),
+            (AclTag::User, 0o6, 1001),
+            (AclTag::GroupObj, 0o4, ACL_UNDEFINED_ID),
+            (AclTag::Mask, 0o7, ACL_UNDEFINED_ID),
+            (AclTag::Other, 0o4, ACL_UNDEFINED_ID),
+        ]))
+        .is_err());
+        assert!(Acl::parse(&raw(&[
+            (AclTag::UserObj, 0o6, ACL_UNDEFINED_ID),
+            (AclTag::GroupObj, 0o4, ACL_UNDEFINED_ID),
+            (AclTag::Other, 0o4, ACL_UNDEFINED_ID),
+            (AclTag::Other, 0o0, ACL_UNDEFINED_ID),
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn permits() {
+        let acl = named();
+        let file = attr(1000, 2000);
+        // the owner is not limited by the mask
+        assert!(acl.permits(&file, &cred(1000, 3000), 0o6));
+        assert!(!acl.permits(&file, &cred(1000, 3000), 0o1));
+        // named entries are limited by the mask
+        assert!(acl.permits(&file, &cred(1001, 3000), 0o6));
+        assert!(!acl.permits(&file, &cred(1001, 3000), 0o1));
+        assert!(acl.permits(&file, &cred(1002, 2000), 0o4));
+        assert!(!acl.permits(&file, &cred(1002, 2000), 0o2));
+        assert!(acl.permits(&file, &cred(1002, 2001), 0o6));
+        assert!(!acl.permits(&file, &cred(1002, 3000), 0o4));
+        let member = cred(1002, 3000).with_groups(vec![2001u32].into());
+        assert!(acl.permits(&file, &member, 0o6));
+        // root may execute only if anyone may
+        assert!(acl.permits(&file, &cred(0, 0), 0o6));
+        assert!(!acl.permits(&file, &cred(0, 0), 0o1));
+    }
+
+    #[test]
+    fn inherit() {
+        let acl = named().inherit(0o640);
+        assert_eq!(acl.mode(), 0o640);
+        assert_eq!(acl.perm_of(AclTag::Mask), 0o4);
+        // named entries are kept, and limited by the new mask
+        assert!(acl.permits(&attr(1000, 2000), &cred(1001, 3000), 0o4));
+        assert!(!acl.permits(&attr(1000, 2000), &cred(1001, 3000), 0o2));
+
+        let minimal = Acl::from_mode(0o755).inherit(0o644);
+        assert_eq!(minimal, Acl::from_mode(0o644));
+    }
+}
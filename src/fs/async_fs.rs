@@ -14,6 +14,7 @@ use fuser::{
 };
 use tracing::trace;
 
+use super::credentials::Credentials;
 use super::error::{FsError, Result};
 use super::reply::{
     Attr, Bmap, Create, Data, Dir, DirPlus, Entry, FsReply, Lock, Lseek, Open, StatFs, Write, Xattr,
@@ -46,7 +47,7 @@ pub trait AsyncFileSystem: Send + Sync {
     async fn destroy(&self) {}
 
     /// Look up a directory entry by name and get its attributes.
-    async fn lookup(&self, _parent: u64, _name: ByteString) -> Result<Entry> {
+    async fn lookup(&self, _cred: &Credentials, _parent: u64, _name: ByteString) -> Result<Entry> {
         Err(FsError::unimplemented())
     }
 
@@ -60,13 +61,14 @@ pub trait AsyncFileSystem: Send + Sync {
     async fn forget(&self, _ino: u64, _nlookup: u64) {}
 
     /// Get file attributes.
-    async fn getattr(&self, _ino: u64) -> Result<Attr> {
+    async fn getattr(&self, _cred: &Credentials, _ino: u64) -> Result<Attr> {
         Err(FsError::unimplemented())
     }
 
     /// Set file attributes.
     async fn setattr(
         &self,
+        _cred: &Credentials,
         _ino: u64,
         _mode: Option<u32>,
         _uid: Option<u32>,
@@ -85,7 +87,7 @@ pub trait AsyncFileSystem: Send + Sync {
     }
 
     /// Read symbolic link.
-    async fn readlink(&self, _ino: u64) -> Result<Data> {
+    async fn readlink(&self, _cred: &Credentials, _ino: u64) -> Result<Data> {
         Err(FsError::unimplemented())
     }
 
@@ -93,11 +95,10 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Create a regular file, character device, block device, fifo or socket node.
     async fn mknod(
         &self,
+        _cred: &Credentials,
         _parent: u64,
         _name: ByteString,
         _mode: u32,
-        _gid: u32,
-        _uid: u32,
         _umask: u32,
         _rdev: u32,
     ) -> Result<Entry> {
@@ -107,31 +108,29 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Create a directory.
     async fn mkdir(
         &self,
+        _cred: &Credentials,
         _parent: u64,
         _name: ByteString,
         _mode: u32,
-        _gid: u32,
-        _uid: u32,
         _umask: u32,
     ) -> Result<Entry> {
         Err(FsError::unimplemented())
     }
 
     /// Remove a file.
-    async fn unlink(&self, _parent: u64, _name: ByteString) -> Result<()> {
+    async fn unlink(&self, _cred: &Credentials, _parent: u64, _name: ByteString) -> Result<()> {
         Err(FsError::unimplemented())
     }
 
     /// Remove a directory.
-    async fn rmdir(&self, _parent: u64, _name: ByteString) -> Result<()> {
+    async fn rmdir(&self, _cred: &Credentials, _parent: u64, _name: ByteString) -> Result<()> {
         Err(FsError::unimplemented())
     }
 
     /// Create a symbolic link.
     async fn symlink(
         &self,
-        _gid: u32,
-        _uid: u32,
+        _cred: &Credentials,
         _parent: u64,
         _name: ByteString,
         _link: ByteString,
@@ -142,6 +141,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Rename a file.
     async fn rename(
         &self,
+        _cred: &Credentials,
         _parent: u64,
         _name: ByteString,
         _newparent: u64,
@@ -152,7 +152,13 @@ pub trait AsyncFileSystem: Send + Sync {
     }
 
     /// Create a hard link.
-    async fn link(&self, _ino: u64, _newparent: u64, _newname: ByteString) -> Result<Entry> {
+    async fn link(
+        &self,
+        _cred: &Credentials,
+        _ino: u64,
+        _newparent: u64,
+        _newname: ByteString,
+    ) -> Result<Entry> {
         Err(FsError::unimplemented())
     }
 
@@ -164,7 +170,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// anything in fh. There are also some flags (direct_io, keep_cache) which the
     /// filesystem may set, to change the way the file is opened. See fuse_file_info
     /// structure in <fuse_common.h> for more details.
-    async fn open(&self, _ino: u64, _flags: i32) -> Result<Open> {
+    async fn open(&self, _cred: &Credentials, _ino: u64, _flags: i32) -> Result<Open> {
         Ok(Open::new(0, 0))
     }
 
@@ -180,6 +186,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// lock_owner: only supported with ABI >= 7.9
     async fn read(
         &self,
+        _cred: &Credentials,
         _ino: u64,
         _fh: u64,
         _offset: i64,
@@ -204,6 +211,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// lock_owner: only supported with ABI >= 7.9
     async fn write(
         &self,
+        _cred: &Credentials,
         _ino: u64,
         _fh: u64,
         _offset: i64,
@@ -225,7 +233,13 @@ pub trait AsyncFileSystem: Send + Sync {
     /// is not forced to flush pending writes. One reason to flush data, is if the
     /// filesystem wants to return write errors. If the filesystem supports file locking
     /// operations (setlk, getlk) it should remove all locks belonging to 'lock_owner'.
-    async fn flush(&self, _ino: u64, _fh: u64, _lock_owner: u64) -> Result<()> {
+    async fn flush(
+        &self,
+        _cred: &Credentials,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+    ) -> Result<()> {
         Err(FsError::unimplemented())
     }
 
@@ -239,6 +253,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// open.
     async fn release(
         &self,
+        _cred: &Credentials,
         _ino: u64,
         _fh: u64,
         _flags: i32,
@@ -251,7 +266,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Synchronize file contents.
     /// If the datasync parameter is non-zero, then only the user data should be flushed,
     /// not the meta data.
-    async fn fsync(&self, _ino: u64, _fh: u64, _datasync: bool) -> Result<()> {
+    async fn fsync(&self, _cred: &Credentials, _ino: u64, _fh: u64, _datasync: bool) -> Result<()> {
         Err(FsError::unimplemented())
     }
 
@@ -262,7 +277,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// anything in fh, though that makes it impossible to implement standard conforming
     /// directory stream operations in case the contents of the directory can change
     /// between opendir and releasedir.
-    async fn opendir(&self, _ino: u64, _flags: i32) -> Result<Open> {
+    async fn opendir(&self, _cred: &Credentials, _ino: u64, _flags: i32) -> Result<Open> {
         Ok(Open::new(0, 0))
     }
 
@@ -271,7 +286,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// requested size. Send an empty buffer on end of stream. fh will contain the
     /// value set by the opendir method, or will be undefined if the opendir method
     /// didn't set any value.
-    async fn readdir(&self, _ino: u64, _fh: u64, offset: i64) -> Result<Dir> {
+    async fn readdir(&self, _cred: &Credentials, _ino: u64, _fh: u64, offset: i64) -> Result<Dir> {
         Ok(Dir::offset(offset as usize))
     }
 
@@ -280,7 +295,13 @@ pub trait AsyncFileSystem: Send + Sync {
     /// requested size. Send an empty buffer on end of stream. fh will contain the
     /// value set by the opendir method, or will be undefined if the opendir method
     /// didn't set any value.
-    async fn readdirplus(&self, _ino: u64, _fh: u64, offset: i64) -> Result<DirPlus> {
+    async fn readdirplus(
+        &self,
+        _cred: &Credentials,
+        _ino: u64,
+        _fh: u64,
+        offset: i64,
+    ) -> Result<DirPlus> {
         Ok(DirPlus::offset(offset as usize))
     }
 
@@ -288,7 +309,13 @@ pub trait AsyncFileSystem: Send + Sync {
     /// For every opendir call there will be exactly one releasedir call. fh will
     /// contain the value set by the opendir method, or will be undefined if the
     /// opendir method didn't set any value.
-    async fn releasedir(&self, _ino: u64, _fh: u64, _flags: i32) -> Result<()> {
+    async fn releasedir(
+        &self,
+        _cred: &Credentials,
+        _ino: u64,
+        _fh: u64,
+        _flags: i32,
+    ) -> Result<()> {
         Err(FsError::unimplemented())
     }
 
@@ -296,18 +323,25 @@ pub trait AsyncFileSystem: Send + Sync {
     /// If the datasync parameter is set, then only the directory contents should
     /// be flushed, not the meta data. fh will contain the value set by the opendir
     /// method, or will be undefined if the opendir method didn't set any value.
-    async fn fsyncdir(&self, _ino: u64, _fh: u64, _datasync: bool) -> Result<()> {
+    async fn fsyncdir(
+        &self,
+        _cred: &Credentials,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+    ) -> Result<()> {
         Err(FsError::unimplemented())
     }
 
     /// Get file system statistics.
-    async fn statfs(&self, _ino: u64) -> Result<StatFs> {
+    async fn statfs(&self, _cred: &Credentials, _ino: u64) -> Result<StatFs> {
         Ok(StatFs::new(0, 0, 0, 0, 0, 512, 255, 0))
     }
 
     /// Set an extended attribute.
     async fn setxattr(
         &self,
+        _cred: &Credentials,
         _ino: u64,
         _name: ByteString,
         _value: Vec<u8>,
@@ -321,7 +355,13 @@ pub trait AsyncFileSystem: Send + Sync {
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
     /// `reply.error(ERANGE)` if it doesn't.
-    async fn getxattr(&self, _ino: u64, _name: ByteString, _size: u32) -> Result<Xattr> {
+    async fn getxattr(
+        &self,
+        _cred: &Credentials,
+        _ino: u64,
+        _name: ByteString,
+        _size: u32,
+    ) -> Result<Xattr> {
         Err(FsError::unimplemented())
     }
 
@@ -329,12 +369,12 @@ pub trait AsyncFileSystem: Send + Sync {
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
     /// `reply.error(ERANGE)` if it doesn't.
-    async fn listxattr(&self, _ino: u64, _size: u32) -> Result<Xattr> {
+    async fn listxattr(&self, _cred: &Credentials, _ino: u64, _size: u32) -> Result<Xattr> {
         Err(FsError::unimplemented())
     }
 
     /// Remove an extended attribute.
-    async fn removexattr(&self, _ino: u64, _name: ByteString) -> Result<()> {
+    async fn removexattr(&self, _cred: &Credentials, _ino: u64, _name: ByteString) -> Result<()> {
         Err(FsError::unimplemented())
     }
 
@@ -342,7 +382,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// This will be called for the access() system call. If the 'default_permissions'
     /// mount option is given, this method is not called. This method is not called
     /// under Linux kernel versions 2.4.x
-    async fn access(&self, _ino: u64, _mask: i32) -> Result<()> {
+    async fn access(&self, _cred: &Credentials, _ino: u64, _mask: i32) -> Result<()> {
         Err(FsError::unimplemented())
     }
 
@@ -358,8 +398,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// and open() methods will be called instead.
     async fn create(
         &self,
-        _uid: u32,
-        _gid: u32,
+        _cred: &Credentials,
         _parent: u64,
         _name: ByteString,
         _mode: u32,
@@ -372,6 +411,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Test for a POSIX file lock.
     async fn getlk(
         &self,
+        _cred: &Credentials,
         _ino: u64,
         _fh: u64,
         _lock_owner: u64,
@@ -392,6 +432,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Hence these are only interesting for network filesystems and similar.
     async fn setlk(
         &self,
+        _cred: &Credentials,
         _ino: u64,
         _fh: u64,
         _lock_owner: u64,
@@ -407,13 +448,20 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Map block index within file to block index within device.
     /// Note: This makes sense only for block device backed filesystems mounted
     /// with the 'blkdev' option
-    async fn bmap(&self, _ino: u64, _blocksize: u32, _idx: u64) -> Result<Bmap> {
+    async fn bmap(
+        &self,
+        _cred: &Credentials,
+        _ino: u64,
+        _blocksize: u32,
+        _idx: u64,
+    ) -> Result<Bmap> {
         Err(FsError::unimplemented())
     }
 
     /// Preallocate or deallocate space to a file
     async fn fallocate(
         &self,
+        _cred: &Credentials,
         _ino: u64,
         _fh: u64,
         _offset: i64,
@@ -424,13 +472,21 @@ pub trait AsyncFileSystem: Send + Sync {
     }
 
     /// Reposition read/write file offset
-    async fn lseek(&self, _ino: u64, _fh: u64, _offset: i64, _whence: i32) -> Result<Lseek> {
+    async fn lseek(
+        &self,
+        _cred: &Credentials,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _whence: i32,
+    ) -> Result<Lseek> {
         Err(FsError::unimplemented())
     }
 
     /// Copy the specified range from the source inode to the destination inode
     async fn copy_file_range(
         &self,
+        _cred: &Credentials,
         _ino_in: u64,
         _fh_in: u64,
         _offset_in: i64,
@@ -476,9 +532,10 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
 
     fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         let name = name.to_string_lossy().to_string().into();
         spawn_reply(req.unique(), reply, async move {
-            async_impl.lookup(parent, name).await
+            async_impl.lookup(&cred, parent, name).await
         });
     }
 
@@ -493,11 +550,10 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
 
     fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
         let async_impl = self.0.clone();
-        spawn_reply(
-            req.unique(),
-            reply,
-            async move { async_impl.getattr(ino).await },
-        );
+        let cred = Credentials::from_request(req);
+        spawn_reply(req.unique(), reply, async move {
+            async_impl.getattr(&cred, ino).await
+        });
     }
 
     fn setattr(
@@ -519,11 +575,12 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyAttr,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
             async_impl
                 .setattr(
-                    ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
-                    flags,
+                    &cred, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime,
+                    bkuptime, flags,
                 )
                 .await
         });
@@ -531,8 +588,9 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
 
     fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.readlink(ino).await
+            async_impl.readlink(&cred, ino).await
         });
     }
 
@@ -547,13 +605,11 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEntry,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         let name = name.to_string_lossy().to_string().into();
-        let uid = req.uid();
-        let gid = req.gid();
-
         spawn_reply(req.unique(), reply, async move {
             async_impl
-                .mknod(parent, name, mode, gid, uid, umask, rdev)
+                .mknod(&cred, parent, name, mode, umask, rdev)
                 .await
         });
     }
@@ -568,28 +624,28 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEntry,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         let name = name.to_string_lossy().to_string().into();
-        let uid = req.uid();
-        let gid = req.gid();
-
         spawn_reply(req.unique(), reply, async move {
-            async_impl.mkdir(parent, name, mode, gid, uid, umask).await
+            async_impl.mkdir(&cred, parent, name, mode, umask).await
         });
     }
 
     fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         let name = name.to_string_lossy().to_string().into();
         spawn_reply(req.unique(), reply, async move {
-            async_impl.unlink(parent, name).await
+            async_impl.unlink(&cred, parent, name).await
         });
     }
 
     fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         let name = name.to_string_lossy().to_string().into();
         spawn_reply(req.unique(), reply, async move {
-            async_impl.rmdir(parent, name).await
+            async_impl.rmdir(&cred, parent, name).await
         });
     }
 
@@ -602,13 +658,11 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEntry,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         let name = name.to_string_lossy().to_string().into();
         let link = link.to_string_lossy().to_string().into();
-        let uid = req.uid();
-        let gid = req.gid();
-
         spawn_reply(req.unique(), reply, async move {
-            async_impl.symlink(gid, uid, parent, name, link).await
+            async_impl.symlink(&cred, parent, name, link).await
         });
     }
 
@@ -623,11 +677,12 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         let name = name.to_string_lossy().to_string().into();
         let newname = newname.to_string_lossy().to_string().into();
         spawn_reply(req.unique(), reply, async move {
             async_impl
-                .rename(parent, name, newparent, newname, flags)
+                .rename(&cred, parent, name, newparent, newname, flags)
                 .await
         });
     }
@@ -641,16 +696,18 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEntry,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         let newname = newname.to_string_lossy().to_string().into();
         spawn_reply(req.unique(), reply, async move {
-            async_impl.link(ino, newparent, newname).await
+            async_impl.link(&cred, ino, newparent, newname).await
         });
     }
 
     fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.open(ino, flags).await
+            async_impl.open(&cred, ino, flags).await
         });
     }
 
@@ -666,9 +723,10 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyData,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
             async_impl
-                .read(ino, fh, offset, size, flags, lock_owner)
+                .read(&cred, ino, fh, offset, size, flags, lock_owner)
                 .await
         });
     }
@@ -686,18 +744,20 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyWrite,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         let data = data.to_owned();
         spawn_reply(req.unique(), reply, async move {
             async_impl
-                .write(ino, fh, offset, data, write_flags, flags, lock_owner)
+                .write(&cred, ino, fh, offset, data, write_flags, flags, lock_owner)
                 .await
         });
     }
 
     fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.flush(ino, fh, lock_owner).await
+            async_impl.flush(&cred, ino, fh, lock_owner).await
         });
     }
 
@@ -712,29 +772,35 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.release(ino, fh, flags, lock_owner, flush).await
+            async_impl
+                .release(&cred, ino, fh, flags, lock_owner, flush)
+                .await
         });
     }
 
     fn fsync(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.fsync(ino, fh, datasync).await
+            async_impl.fsync(&cred, ino, fh, datasync).await
         });
     }
 
     fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.opendir(ino, flags).await
+            async_impl.opendir(&cred, ino, flags).await
         });
     }
 
     fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.readdir(ino, fh, offset).await
+            async_impl.readdir(&cred, ino, fh, offset).await
         });
     }
 
@@ -747,25 +813,26 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyDirectoryPlus,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.readdirplus(ino, fh, offset).await
+            async_impl.readdirplus(&cred, ino, fh, offset).await
         });
     }
 
     fn fsyncdir(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.fsyncdir(ino, fh, datasync).await
+            async_impl.fsyncdir(&cred, ino, fh, datasync).await
         });
     }
 
     fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
         let async_impl = self.0.clone();
-        spawn_reply(
-            req.unique(),
-            reply,
-            async move { async_impl.statfs(ino).await },
-        );
+        let cred = Credentials::from_request(req);
+        spawn_reply(req.unique(), reply, async move {
+            async_impl.statfs(&cred, ino).await
+        });
     }
 
     fn setxattr(
@@ -779,39 +846,46 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         let name = name.to_string_lossy().to_string().into();
         let value = value.to_owned();
         spawn_reply(req.unique(), reply, async move {
-            async_impl.setxattr(ino, name, value, flags, position).await
+            async_impl
+                .setxattr(&cred, ino, name, value, flags, position)
+                .await
         });
     }
 
     fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         let name = name.to_string_lossy().to_string().into();
         spawn_reply(req.unique(), reply, async move {
-            async_impl.getxattr(ino, name, size).await
+            async_impl.getxattr(&cred, ino, name, size).await
         });
     }
 
     fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.listxattr(ino, size).await
+            async_impl.listxattr(&cred, ino, size).await
         });
     }
 
     fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         let name = name.to_string_lossy().to_string().into();
         spawn_reply(req.unique(), reply, async move {
-            async_impl.removexattr(ino, name).await
+            async_impl.removexattr(&cred, ino, name).await
         });
     }
     fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.access(ino, mask).await
+            async_impl.access(&cred, ino, mask).await
         });
     }
 
@@ -825,14 +899,12 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         flags: i32,
         reply: ReplyCreate,
     ) {
-        let uid = req.uid();
-        let gid = req.gid();
-
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         let name = name.to_string_lossy().to_string().into();
         spawn_reply(req.unique(), reply, async move {
             async_impl
-                .create(uid, gid, parent, name, mode, umask, flags)
+                .create(&cred, parent, name, mode, umask, flags)
                 .await
         });
     }
@@ -850,9 +922,10 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyLock,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
             async_impl
-                .getlk(ino, fh, lock_owner, start, end, typ, pid)
+                .getlk(&cred, ino, fh, lock_owner, start, end, typ, pid)
                 .await
         });
     }
@@ -871,17 +944,19 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
             async_impl
-                .setlk(ino, fh, lock_owner, start, end, typ, pid, sleep)
+                .setlk(&cred, ino, fh, lock_owner, start, end, typ, pid, sleep)
                 .await
         });
     }
 
     fn bmap(&mut self, req: &Request, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.bmap(ino, blocksize, idx).await
+            async_impl.bmap(&cred, ino, blocksize, idx).await
         });
     }
 
@@ -896,8 +971,11 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.fallocate(ino, fh, offset, length, mode).await
+            async_impl
+                .fallocate(&cred, ino, fh, offset, length, mode)
+                .await
         });
     }
 
@@ -911,8 +989,9 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyLseek,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
-            async_impl.lseek(ino, fh, offset, whence).await
+            async_impl.lseek(&cred, ino, fh, offset, whence).await
         });
     }
 
@@ -930,10 +1009,11 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyWrite,
     ) {
         let async_impl = self.0.clone();
+        let cred = Credentials::from_request(req);
         spawn_reply(req.unique(), reply, async move {
             async_impl
                 .copy_file_range(
-                    ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags,
+                    &cred, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags,
                 )
                 .await
         });
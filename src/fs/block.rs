@@ -1,3 +1,9 @@
+use std::mem::size_of;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::{FsError, Result};
+use super::serialize::{deserialize, serialize, ENCODING};
 use super::tikv_fs::TiFs;
 
 type Block = Vec<u8>;
@@ -5,3 +11,140 @@ type Block = Vec<u8>;
 pub fn empty_block() -> Block {
     vec![0; TiFs::BLOCK_SIZE as usize]
 }
+
+/// A block borrowed from another inode, whose data is stored in `Block { ino: owner, block }`.
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy, Deserialize, Serialize)]
+pub struct BlockLink {
+    pub owner: u64,
+}
+
+/// Inodes linking to a block owned by another inode.
+/// The reference count of the block is `sharers.len() + 1`.
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Default, Deserialize, Serialize)]
+pub struct BlockShare {
+    pub sharers: Vec<u64>,
+}
+
+impl BlockLink {
+    pub const fn new(owner: u64) -> Self {
+        Self { owner }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "block link",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "block link",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
+impl BlockShare {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "block share",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "block share",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
+/// A small write into a block, stored apart from the block until it is folded into it.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct BlockDelta {
+    pub offset: usize,
+    pub data: Vec<u8>,
+}
+
+impl BlockDelta {
+    pub const fn new(offset: usize, data: Vec<u8>) -> Self {
+        Self { offset, data }
+    }
+
+    pub fn apply(&self, block: &mut Block) {
+        let end = self.offset + self.data.len();
+        if block.len() < end {
+            block.resize(end, 0);
+        }
+        block[self.offset..end].copy_from_slice(&self.data);
+    }
+
+    /// Encode as the offset in big-endian followed by the data.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut value = Vec::with_capacity(size_of::<u64>() + self.data.len());
+        value.extend((self.offset as u64).to_be_bytes().iter());
+        value.extend_from_slice(&self.data);
+        value
+    }
+
+    pub fn decode(value: &[u8]) -> Result<Self> {
+        let invalid_delta = || FsError::InvalidDelta;
+        let offset = u64::from_be_bytes(*value.array_chunks().next().ok_or_else(invalid_delta)?);
+        let data = &value[size_of::<u64>()..];
+        match offset.checked_add(data.len() as u64) {
+            Some(end) if end <= TiFs::BLOCK_SIZE => (),
+            _ => return Err(invalid_delta()),
+        }
+        Ok(Self::new(offset as usize, data.to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        let delta = BlockDelta::new(42, b"delta".to_vec());
+        let value = delta.encode();
+        assert_eq!(&value[..size_of::<u64>()], &42u64.to_be_bytes());
+        assert_eq!(BlockDelta::decode(&value).unwrap(), delta);
+
+        let empty = BlockDelta::new(0, Vec::new());
+        assert_eq!(BlockDelta::decode(&empty.encode()).unwrap(), empty);
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert!(BlockDelta::decode(&[0; 4]).is_err());
+        let overflow = BlockDelta::new(TiFs::BLOCK_SIZE as usize - 1, vec![1, 2]);
+        assert!(BlockDelta::decode(&overflow.encode()).is_err());
+        let mut wrapping = std::u64::MAX.to_be_bytes().to_vec();
+        wrapping.extend_from_slice(&[1, 2]);
+        assert!(BlockDelta::decode(&wrapping).is_err());
+        let tail = BlockDelta::new(TiFs::BLOCK_SIZE as usize - 2, vec![1, 2]);
+        assert_eq!(BlockDelta::decode(&tail.encode()).unwrap(), tail);
+    }
+
+    #[test]
+    fn apply_in_order() {
+        let mut block = empty_block();
+        BlockDelta::new(1, vec![1, 1, 1]).apply(&mut block);
+        BlockDelta::new(2, vec![2, 2]).apply(&mut block);
+        assert_eq!(&block[..5], &[0, 1, 2, 2, 0]);
+        assert_eq!(block.len(), TiFs::BLOCK_SIZE as usize);
+    }
+
+    #[test]
+    fn apply_to_short_block() {
+        let mut block = vec![7; 2];
+        BlockDelta::new(4, vec![1, 2]).apply(&mut block);
+        assert_eq!(block, vec![7, 7, 0, 0, 1, 2]);
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs::read_to_string;
+use std::sync::Arc;
+
+use fuser::Request;
+
+/// Credentials of the process sending a request.
+///
+/// Supplementary groups are empty until they are resolved by the mount, before any transaction.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+    pub groups: Arc<[u32]>,
+}
+
+impl Credentials {
+    pub fn new(uid: u32, gid: u32, pid: u32) -> Self {
+        Self {
+            uid,
+            gid,
+            pid,
+            groups: Arc::new([]),
+        }
+    }
+
+    pub fn from_request(req: &Request) -> Self {
+        Self::new(req.uid(), req.gid(), req.pid())
+    }
+
+    pub const fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    pub fn with_groups(&self, groups: Arc<[u32]>) -> Self {
+        Self {
+            groups,
+            ..self.clone()
+        }
+    }
+
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+
+    /// Supplementary groups are not sent by the kernel, read them from procfs like libfuse does.
+    /// It returns no groups if the process has exited or there is no procfs.
+    ///
+    /// It blocks on reading procfs, so it must be called off the executor.
+    pub fn read_groups(pid: u32) -> Vec<u32> {
+        read_to_string(format!("/proc/{}/status", pid))
+            .ok()
+            .and_then(|status| {
+                status
+                    .lines()
+                    .find(|line| line.starts_with("Groups:"))
+                    .map(|line| {
+                        line["Groups:".len()..]
+                            .split_whitespace()
+                            .filter_map(|gid| gid.parse().ok())
+                            .collect()
+                    })
+            })
+            .unwrap_or_default()
+    }
+
+    /// Whether the process has a pending signal which is neither blocked nor ignored, so that
+    /// a request it is blocked on can be cancelled like the kernel does, as interrupts are not
+    /// delivered to us. It reads the signal masks from procfs.
+    ///
+    /// It blocks on reading procfs, so it must be called off the executor.
+    pub fn interrupted(pid: u32) -> bool {
+        let status = match read_to_string(format!("/proc/{}/status", pid)) {
+            Ok(status) => status,
+            Err(_) => return false,
+        };
+        let mask = |name: &str| {
+            status
+                .lines()
+                .filter(|line| line.starts_with(name))
+                .filter_map(|line| line.split_whitespace().nth(1))
+                .filter_map(|mask| u64::from_str_radix(mask, 16).ok())
+                .fold(0, |masks, mask| masks | mask)
+        };
+        let pending = mask("SigPnd:") | mask("ShdPnd:");
+        pending & !mask("SigBlk:") & !mask("SigIgn:") != 0
+    }
+}
@@ -1,6 +1,8 @@
 use thiserror::Error;
 use tracing::error;
 
+use super::quota::QuotaKind;
+
 #[derive(Error, Debug)]
 pub enum FsError {
     #[error("unimplemented")]
@@ -28,21 +30,88 @@ pub enum FsError {
     #[error("cannot find inode({inode})")]
     InodeNotFound { inode: u64 },
 
+    #[error("inode({ino}) has a pending intent")]
+    PendingIntent { ino: u64 },
+
+    #[error("inode({ino}) is busy")]
+    InodeBusy { ino: u64 },
+
+    #[error("intent of inode({ino}) is lost")]
+    IntentLost { ino: u64 },
+
     #[error("cannot find {ino}({fh})")]
     FhNotFound { ino: u64, fh: u64 },
 
+    #[error("{ino}({fh}) is not opened for {access}")]
+    BadAccessMode {
+        ino: u64,
+        fh: u64,
+        access: &'static str,
+    },
+
     #[error("invalid offset({offset}) of ino({ino})")]
     InvalidOffset { ino: u64, offset: i64 },
 
+    #[error("inode({ino}) belongs to a snapshot and is read-only")]
+    ReadOnly { ino: u64 },
+
     #[error("unknown whence({whence})")]
     UnknownWhence { whence: i32 },
 
+    #[error("no data or hole of ino({ino}) from offset({offset})")]
+    NoSuchOffset { ino: u64, offset: i64 },
+
     #[error("cannot find block(<{inode}>[{block}])")]
     BlockNotFound { inode: u64, block: u64 },
 
+    #[error("file({file}) is not a directory")]
+    NotDirectory { file: String },
+
+    #[error("file({file}) is a directory")]
+    IsDirectory { file: String },
+
     #[error("dir({dir}) not empty")]
     DirNotEmpty { dir: String },
 
+    #[error("cannot move inode({ino}) into its subtree({parent})")]
+    InvalidRename { ino: u64, parent: u64 },
+
+    #[error("cannot move inode({ino}) into project({project})")]
+    CrossProject { ino: u64, project: u32 },
+
+    #[error("unknown rename flags({flags:#x})")]
+    UnknownRenameFlags { flags: u32 },
+
+    #[error("xattr({name}) of inode({ino}) not found")]
+    XattrNotFound { ino: u64, name: String },
+
+    #[error("xattr({name}) of inode({ino}) already exist")]
+    XattrExist { ino: u64, name: String },
+
+    #[error("name of xattr({name}) is too long")]
+    XattrNameTooLong { name: String },
+
+    #[error("value({size}) of xattr is too large")]
+    XattrTooLarge { size: usize },
+
+    #[error("invalid acl")]
+    InvalidAcl,
+
+    #[error("permission({mask:o}) of inode({ino}) denied")]
+    PermissionDenied { ino: u64, mask: i32 },
+
+    #[error("operation on inode({ino}) not permitted")]
+    NotPermitted { ino: u64 },
+
+    #[error("quota of {kind:?}({id}) exceeded")]
+    QuotaExceeded { kind: QuotaKind, id: u32 },
+
+    #[error("no space left on the volume")]
+    NoSpace,
+
+    #[error("size({size}) is out of the buffer({limit})")]
+    OutOfRange { size: usize, limit: u32 },
+
     #[error("invalid string")]
     InvalidStr,
 
@@ -63,6 +132,18 @@ pub enum FsError {
 
     #[error("invalid lock")]
     InvalidLock,
+
+    #[error("lock on inode({ino}) conflicts with owner({owner})")]
+    LockConflict { ino: u64, owner: u64 },
+
+    #[error("lease of session({session}) expired")]
+    LeaseExpired { session: u64 },
+
+    #[error("invalid block delta")]
+    InvalidDelta,
+
+    #[error("request of process({pid}) is interrupted")]
+    Interrupted { pid: u32 },
 }
 
 pub type Result<T> = std::result::Result<T, FsError>;
@@ -106,15 +187,45 @@ impl Into<libc::c_int> for FsError {
             FileNotFound { file: _ } => libc::ENOENT,
             FileExist { file: _ } => libc::EEXIST,
             InodeNotFound { inode: _ } => libc::EFAULT,
+            PendingIntent { ino: _ } => libc::EAGAIN,
+            InodeBusy { ino: _ } => libc::EAGAIN,
+            IntentLost { ino: _ } => libc::EIO,
             FhNotFound { ino: _, fh: _ } => libc::EBADF,
+            BadAccessMode {
+                ino: _,
+                fh: _,
+                access: _,
+            } => libc::EBADF,
             InvalidOffset { ino: _, offset: _ } => libc::EINVAL,
             UnknownWhence { whence: _ } => libc::EINVAL,
+            ReadOnly { ino: _ } => libc::EROFS,
+            NoSuchOffset { ino: _, offset: _ } => libc::ENXIO,
             BlockNotFound { inode: _, block: _ } => libc::EINVAL,
+            NotDirectory { file: _ } => libc::ENOTDIR,
+            IsDirectory { file: _ } => libc::EISDIR,
             DirNotEmpty { dir: _ } => libc::ENOTEMPTY,
+            InvalidRename { ino: _, parent: _ } => libc::EINVAL,
+            CrossProject { ino: _, project: _ } => libc::EXDEV,
+            UnknownRenameFlags { flags: _ } => libc::EINVAL,
+            XattrNotFound { ino: _, name: _ } => libc::ENODATA,
+            XattrExist { ino: _, name: _ } => libc::EEXIST,
+            XattrNameTooLong { name: _ } => libc::ERANGE,
+            XattrTooLarge { size: _ } => libc::E2BIG,
+            OutOfRange { size: _, limit: _ } => libc::ERANGE,
+            QuotaExceeded { kind: _, id: _ } => libc::EDQUOT,
+            NoSpace => libc::ENOSPC,
+            InvalidAcl => libc::EINVAL,
+            PermissionDenied { ino: _, mask: _ } => libc::EACCES,
+            NotPermitted { ino: _ } => libc::EPERM,
             UnknownFileType => libc::EINVAL,
             KeyError(_) => libc::EAGAIN,
             RetryTimesExcess(_) => libc::EAGAIN,
             InvalidStr => libc::EINVAL,
+            InvalidLock => libc::EINVAL,
+            LockConflict { ino: _, owner: _ } => libc::EAGAIN,
+            Interrupted { pid: _ } => libc::EINTR,
+            LeaseExpired { session: _ } => libc::ENOLCK,
+            InvalidDelta => libc::EIO,
             _ => libc::EFAULT,
         }
     }
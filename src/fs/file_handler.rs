@@ -1,38 +1,42 @@
-use serde::{Deserialize, Serialize};
+use libc::{O_ACCMODE, O_APPEND, O_RDONLY, O_WRONLY};
 
-use super::error::{FsError, Result};
-use super::serialize::{deserialize, serialize, ENCODING};
-
-#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy, Deserialize, Serialize)]
+/// An opened file, which is only kept in the mount opening it.
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
 pub struct FileHandler {
-    // TODO: add open flags
+    pub ino: u64,
     pub cursor: u64,
+    pub flags: i32,
+    /// The session recording the inode as opened, which may be replaced after the handler
+    /// is opened if the lease expires.
+    pub session: u64,
 }
 
 impl FileHandler {
-    pub const fn new(cursor: u64) -> Self {
-        Self { cursor }
+    pub const fn new(ino: u64, cursor: u64, flags: i32, session: u64) -> Self {
+        Self {
+            ino,
+            cursor,
+            flags,
+            session,
+        }
+    }
+
+    pub const fn readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
     }
 
-    pub fn serialize(&self) -> Result<Vec<u8>> {
-        serialize(self).map_err(|err| FsError::Serialize {
-            target: "file handler",
-            typ: ENCODING,
-            msg: err.to_string(),
-        })
+    pub const fn writable(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
-        deserialize(bytes).map_err(|err| FsError::Serialize {
-            target: "file handler",
-            typ: ENCODING,
-            msg: err.to_string(),
-        })
+    /// Whether each write goes to the end of file, regardless of the cursor.
+    pub const fn append(&self) -> bool {
+        self.flags & O_APPEND != 0
     }
 }
 
 impl Default for FileHandler {
     fn default() -> Self {
-        Self::new(0)
+        Self::new(0, 0, 0, 0)
     }
 }
@@ -1,30 +1,52 @@
 use super::error::{FsError, Result};
+use super::intent::Intent;
 use super::serialize::{deserialize, serialize, ENCODING};
 use super::tikv_fs::TiFs;
 use fuser::FileAttr;
-use libc::F_UNLCK;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
 use std::ops::{Deref, DerefMut};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub struct LockState {
-    pub owner_set: HashSet<u64>,
-    pub lk_type: i32,
+/// When to update the access time of an inode on reading.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AtimePolicy {
+    NoAtime,
+    RelAtime,
+    StrictAtime,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Inode {
     pub file_attr: FileAttr,
-    pub lock_state: LockState,
     pub inline_data: Option<Vec<u8>>,
-    pub next_fh: u64,
-    pub opened_fh: u64,
+    /// Whether some blocks of this inode may be shared with snapshots.
+    pub cow: bool,
+    /// Whether the inode belongs to a snapshot, which is never changed through the mount.
+    pub readonly: bool,
+    pub intent: Option<Intent>,
+    /// Generation of the inode number, which differs if the number is ever reused.
+    pub generation: u64,
+    /// Project the inode is charged to, 0 for none.
+    pub project: u32,
+    /// Number of blocks stored for the inode, including blocks linked from other inodes.
+    pub allocated: u64,
 }
 
 impl Inode {
-    fn update_blocks(&mut self) {
-        self.blocks = (self.size + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
+    /// Size of the unit of `blocks`, which is always 512 bytes for `st_blocks`.
+    pub const SECTOR_SIZE: u64 = 512;
+
+    /// Count the storage of the inode in sectors, which is its inline data or its allocated blocks.
+    ///
+    /// The allocation is clamped to the size, so that the short tail block of a file is not
+    /// counted as a full block unless there are holes before it.
+    pub fn update_blocks(&mut self) {
+        let sectors = (self.size + Self::SECTOR_SIZE - 1) / Self::SECTOR_SIZE;
+        self.blocks = if self.inline_data.is_some() {
+            sectors
+        } else {
+            sectors.min(self.allocated * (TiFs::BLOCK_SIZE / Self::SECTOR_SIZE))
+        };
     }
 
     pub fn set_size(&mut self, size: u64) {
@@ -32,6 +54,87 @@ impl Inode {
         self.update_blocks();
     }
 
+    /// Mark the content as modified, which changes the status as well.
+    pub fn touch_mtime(&mut self) {
+        let now = SystemTime::now();
+        self.mtime = now;
+        self.ctime = now;
+    }
+
+    /// Take the creation time as the generation, so that a reused inode number
+    /// never gets a generation it had before.
+    pub fn init_generation(&mut self) {
+        self.generation = self
+            .crtime
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or_default();
+    }
+
+    /// Attributes stored in the inode key, without the size, times and inline data.
+    pub fn stat(&self) -> Self {
+        let mut file_attr = self.file_attr;
+        file_attr.size = 0;
+        file_attr.blocks = 0;
+        file_attr.atime = UNIX_EPOCH;
+        file_attr.mtime = UNIX_EPOCH;
+        file_attr.ctime = UNIX_EPOCH;
+        Self {
+            file_attr,
+            inline_data: None,
+            cow: self.cow,
+            readonly: self.readonly,
+            intent: self.intent.clone(),
+            generation: self.generation,
+            project: self.project,
+            allocated: 0,
+        }
+    }
+
+    pub fn times(&self) -> InodeTimes {
+        InodeTimes {
+            size: self.size,
+            blocks: self.blocks,
+            allocated: self.allocated,
+            mtime: self.mtime,
+            ctime: self.ctime,
+        }
+    }
+
+    pub fn access_time(&self) -> InodeAtime {
+        InodeAtime { atime: self.atime }
+    }
+
+    /// Put an inode together from the values of its keys.
+    pub fn assemble(
+        stat: Self,
+        times: InodeTimes,
+        atime: InodeAtime,
+        inline_data: Option<Vec<u8>>,
+    ) -> Self {
+        let mut inode = stat;
+        inode.size = times.size;
+        inode.blocks = times.blocks;
+        inode.allocated = times.allocated;
+        inode.atime = atime.atime;
+        inode.mtime = times.mtime;
+        inode.ctime = times.ctime;
+        inode.inline_data = inline_data;
+        inode
+    }
+
+    /// Copy attributes and inline data into a new inode.
+    pub fn fork(&self, ino: u64) -> Self {
+        let mut inode = Inode::from(self.file_attr);
+        inode.ino = ino;
+        inode.inline_data = self.inline_data.clone();
+        inode.cow = self.cow;
+        inode.generation = self.generation;
+        inode.project = self.project;
+        inode.allocated = self.allocated;
+        inode
+    }
+
     pub fn serialize(&self) -> Result<Vec<u8>> {
         serialize(self).map_err(|err| FsError::Serialize {
             target: "inode",
@@ -49,14 +152,71 @@ impl Inode {
     }
 }
 
+/// The size, allocation and times of an inode, which are changed by almost every write,
+/// so they are stored apart from other attributes to keep them from conflicting.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct InodeTimes {
+    pub size: u64,
+    pub blocks: u64,
+    pub allocated: u64,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+}
+
+impl InodeTimes {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "inode times",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "inode times",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
+/// The access time of an inode, which is changed by reads, so it is stored apart from the
+/// times changed by writes to keep reads from conflicting with them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct InodeAtime {
+    pub atime: SystemTime,
+}
+
+impl InodeAtime {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "inode atime",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "inode atime",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
 impl From<FileAttr> for Inode {
     fn from(attr: FileAttr) -> Self {
         Inode {
             file_attr: attr,
-            lock_state: LockState::new(HashSet::new(), F_UNLCK),
             inline_data: None,
-            next_fh: 0,
-            opened_fh: 0,
+            cow: false,
+            readonly: false,
+            intent: None,
+            generation: 0,
+            project: 0,
+            allocated: 0,
         }
     }
 }
@@ -67,12 +227,6 @@ impl From<Inode> for FileAttr {
     }
 }
 
-impl From<Inode> for LockState {
-    fn from(inode: Inode) -> Self {
-        inode.lock_state
-    }
-}
-
 impl Deref for Inode {
     type Target = FileAttr;
 
@@ -87,8 +241,33 @@ impl DerefMut for Inode {
     }
 }
 
-impl LockState {
-    pub fn new(owner_set: HashSet<u64>, lk_type: i32) -> LockState {
-        LockState { owner_set, lk_type }
+impl AtimePolicy {
+    const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Whether the access time of `attr` should be updated if it's accessed now.
+    ///
+    /// With `relatime`, the access time is updated only if it's earlier than the last
+    /// modification or change, or it's older than a day. Inodes of snapshots are never updated.
+    pub fn need_update(&self, attr: &Inode) -> bool {
+        use AtimePolicy::*;
+
+        if attr.readonly {
+            return false;
+        }
+        match self {
+            NoAtime => false,
+            StrictAtime => true,
+            RelAtime => {
+                attr.atime <= attr.mtime
+                    || attr.atime <= attr.ctime
+                    || attr.atime + Self::RELATIME_INTERVAL <= SystemTime::now()
+            }
+        }
+    }
+}
+
+impl Default for AtimePolicy {
+    fn default() -> Self {
+        AtimePolicy::RelAtime
     }
 }
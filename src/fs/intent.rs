@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+
+use super::tikv_fs::TiFs;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum IntentKind {
+    /// Blocks beyond the new size are being deleted, completed on the next access.
+    Truncate,
+    /// A write is staging its blocks across transactions, discarded if its deadline passes.
+    Write,
+    /// A finished write is swapping its staged blocks in, completed on the next access.
+    Commit,
+    /// The inode is being reclaimed along with its blocks.
+    Remove,
+    /// Blocks of `target` are being shared with this inode from the end, in batches.
+    Share,
+    /// Blocks of this inode are being shared with `target`, which is done by its `Share` intent.
+    Lend,
+    /// Entries under the directory `target` are being copied into this directory, in batches.
+    Clone,
+    /// Entries under this directory are being removed in batches, and those under `target`
+    /// are copied in afterwards if it's not 0.
+    Empty,
+}
+
+/// An operation on an inode which is applied in several transactions.
+///
+/// Blocks in `[ceil(size / BLOCK_SIZE), end_block)` are deleted to complete it, staged blocks
+/// in `[size / BLOCK_SIZE, end_block)` are swapped in or discarded for writes, or blocks are
+/// shared with the inode `target` for sharing intents. Tree intents of a directory walk the
+/// directories queued in its `TreeTask` keys instead.
+///
+/// The deadline is in the physical time of TiKV timestamps in milliseconds, so that all
+/// mounts agree on it whatever their local clocks are.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Intent {
+    pub kind: IntentKind,
+    pub size: u64,
+    pub end_block: u64,
+    pub deadline: u64,
+    pub target: u64,
+}
+
+impl Intent {
+    pub fn new(kind: IntentKind, size: u64, end_block: u64, now: u64) -> Self {
+        Self {
+            kind,
+            size,
+            end_block,
+            deadline: now + TiFs::INTENT_TIMEOUT.as_millis() as u64,
+            target: 0,
+        }
+    }
+
+    /// Share blocks in `[0, end_block)` between an inode and `target`.
+    pub fn share(kind: IntentKind, target: u64, end_block: u64, now: u64) -> Self {
+        Self {
+            target,
+            ..Self::new(kind, 0, end_block, now)
+        }
+    }
+
+    /// Copy the tree under `target` into a directory, or empty the directory first.
+    pub fn tree(kind: IntentKind, target: u64, now: u64) -> Self {
+        Self::share(kind, target, 0, now)
+    }
+
+    pub fn renew(&mut self, now: u64) {
+        self.deadline = now + TiFs::INTENT_TIMEOUT.as_millis() as u64;
+    }
+
+    /// Give up the intent, so that the next access rolls it back.
+    pub fn expire(&mut self) {
+        self.deadline = 0;
+    }
+
+    pub fn expired(&self, now: u64) -> bool {
+        self.deadline <= now
+    }
+
+    /// Whether another transaction is still working on this intent.
+    pub fn busy(&self, now: u64) -> bool {
+        self.kind == IntentKind::Write && !self.expired(now)
+    }
+}
@@ -4,24 +4,103 @@ use std::ops::Range;
 use tikv_client::Key;
 
 use super::error::{FsError, Result};
+use super::quota::QuotaKind;
 
 pub const ROOT_INODE: u64 = fuser::FUSE_ROOT_ID;
+pub const SNAPSHOTS_INODE: u64 = std::u64::MAX;
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
 pub enum ScopedKey<'a> {
     Meta,
     Inode(u64),
-    Block { ino: u64, block: u64 },
-    FileHandler { ino: u64, handler: u64 },
-    FileIndex { parent: u64, name: &'a str },
+    Block {
+        ino: u64,
+        block: u64,
+    },
+    Opened {
+        ino: u64,
+        session: u64,
+    },
+    FileIndex {
+        parent: u64,
+        name: &'a str,
+    },
+    BlockLink {
+        ino: u64,
+        block: u64,
+    },
+    BlockShare {
+        ino: u64,
+        block: u64,
+    },
+    Snapshot {
+        name: &'a str,
+    },
+    Xattr {
+        ino: u64,
+        name: &'a str,
+    },
+    Quota {
+        kind: QuotaKind,
+        id: u32,
+    },
+    Usage(u64),
+    Lock(u64),
+    Session(u64),
+    SessionOpened {
+        session: u64,
+        ino: u64,
+    },
+    Times(u64),
+    Inline(u64),
+    Atime(u64),
+    Delta {
+        ino: u64,
+        block: u64,
+        seq: u64,
+    },
+    TreeTask {
+        root: u64,
+        dir: u64,
+    },
+    TreeFork {
+        root: u64,
+        ino: u64,
+    },
+    Staged {
+        ino: u64,
+        block: u64,
+    },
+    QuotaUsage {
+        kind: QuotaKind,
+        id: u32,
+        shard: u64,
+    },
 }
 
 impl<'a> ScopedKey<'a> {
     const META: u8 = 0;
     const INODE: u8 = 1;
     const BLOCK: u8 = 2;
-    const HANDLER: u8 = 3;
+    const OPENED: u8 = 3;
     const INDEX: u8 = 4;
+    const BLOCK_LINK: u8 = 5;
+    const BLOCK_SHARE: u8 = 6;
+    const SNAPSHOT: u8 = 7;
+    const XATTR: u8 = 8;
+    const QUOTA: u8 = 9;
+    const USAGE: u8 = 10;
+    const LOCK: u8 = 11;
+    const SESSION: u8 = 12;
+    const SESSION_OPENED: u8 = 13;
+    const TIMES: u8 = 14;
+    const INLINE: u8 = 15;
+    const DELTA: u8 = 16;
+    const TREE_TASK: u8 = 17;
+    const TREE_FORK: u8 = 18;
+    const STAGED: u8 = 19;
+    const QUOTA_USAGE: u8 = 20;
+    const ATIME: u8 = 21;
 
     pub const fn meta() -> Self {
         Self::Meta
@@ -39,19 +118,143 @@ impl<'a> ScopedKey<'a> {
         Self::inode(ROOT_INODE)
     }
 
-    pub const fn handler(ino: u64, handler: u64) -> Self {
-        Self::FileHandler { ino, handler }
+    pub const fn opened(ino: u64, session: u64) -> Self {
+        Self::Opened { ino, session }
     }
 
     pub fn index(parent: u64, name: &'a str) -> Self {
         Self::FileIndex { parent, name }
     }
 
+    pub const fn block_link(ino: u64, block: u64) -> Self {
+        Self::BlockLink { ino, block }
+    }
+
+    pub const fn block_share(ino: u64, block: u64) -> Self {
+        Self::BlockShare { ino, block }
+    }
+
+    pub fn snapshot(name: &'a str) -> Self {
+        Self::Snapshot { name }
+    }
+
+    pub fn xattr(ino: u64, name: &'a str) -> Self {
+        Self::Xattr { ino, name }
+    }
+
+    pub const fn quota(kind: QuotaKind, id: u32) -> Self {
+        Self::Quota { kind, id }
+    }
+
+    pub const fn usage(shard: u64) -> Self {
+        Self::Usage(shard)
+    }
+
+    pub const fn lock(ino: u64) -> Self {
+        Self::Lock(ino)
+    }
+
+    pub const fn session(id: u64) -> Self {
+        Self::Session(id)
+    }
+
+    pub const fn session_opened(session: u64, ino: u64) -> Self {
+        Self::SessionOpened { session, ino }
+    }
+
+    pub const fn times(ino: u64) -> Self {
+        Self::Times(ino)
+    }
+
+    pub const fn inline(ino: u64) -> Self {
+        Self::Inline(ino)
+    }
+
+    pub const fn atime(ino: u64) -> Self {
+        Self::Atime(ino)
+    }
+
+    pub const fn delta(ino: u64, block: u64, seq: u64) -> Self {
+        Self::Delta { ino, block, seq }
+    }
+
+    pub const fn tree_task(root: u64, dir: u64) -> Self {
+        Self::TreeTask { root, dir }
+    }
+
+    pub const fn tree_fork(root: u64, ino: u64) -> Self {
+        Self::TreeFork { root, ino }
+    }
+
+    pub const fn staged(ino: u64, block: u64) -> Self {
+        Self::Staged { ino, block }
+    }
+
+    pub const fn quota_usage(kind: QuotaKind, id: u32, shard: u64) -> Self {
+        Self::QuotaUsage { kind, id, shard }
+    }
+
     pub fn block_range(ino: u64, block_range: Range<u64>) -> Range<Key> {
         debug_assert_ne!(0, ino);
         Self::block(ino, block_range.start).into()..Self::block(ino, block_range.end).into()
     }
 
+    pub fn block_link_range(ino: u64, block_range: Range<u64>) -> Range<Key> {
+        debug_assert_ne!(0, ino);
+        Self::block_link(ino, block_range.start).into()
+            ..Self::block_link(ino, block_range.end).into()
+    }
+
+    pub fn delta_range(ino: u64, block_range: Range<u64>) -> Range<Key> {
+        debug_assert_ne!(0, ino);
+        Self::delta(ino, block_range.start, 0).into()..Self::delta(ino, block_range.end, 0).into()
+    }
+
+    pub fn tree_task_range(root: u64) -> Range<Key> {
+        Self::tree_task(root, 0).into()..Self::tree_task(root + 1, 0).into()
+    }
+
+    pub fn tree_fork_range(root: u64) -> Range<Key> {
+        Self::tree_fork(root, 0).into()..Self::tree_fork(root + 1, 0).into()
+    }
+
+    pub fn staged_range(ino: u64, block_range: Range<u64>) -> Range<Key> {
+        debug_assert_ne!(0, ino);
+        Self::staged(ino, block_range.start).into()..Self::staged(ino, block_range.end).into()
+    }
+
+    pub fn snapshot_range() -> Range<Key> {
+        Key::from(vec![Self::SNAPSHOT])..Key::from(vec![Self::SNAPSHOT + 1])
+    }
+
+    pub fn quota_range() -> Range<Key> {
+        Key::from(vec![Self::QUOTA])..Key::from(vec![Self::QUOTA + 1])
+    }
+
+    pub fn quota_usage_range(kind: QuotaKind, id: u32) -> Range<Key> {
+        Self::quota_usage(kind, id, 0).into()..Self::quota_usage(kind, id, std::u64::MAX).into()
+    }
+
+    pub fn usage_range() -> Range<Key> {
+        Key::from(vec![Self::USAGE])..Key::from(vec![Self::USAGE + 1])
+    }
+
+    pub fn session_range() -> Range<Key> {
+        Key::from(vec![Self::SESSION])..Key::from(vec![Self::SESSION + 1])
+    }
+
+    pub fn opened_range(ino: u64) -> Range<Key> {
+        Self::opened(ino, 0).into()..Self::opened(ino + 1, 0).into()
+    }
+
+    pub fn session_opened_range(session: u64) -> Range<Key> {
+        Self::session_opened(session, 0).into()..Self::session_opened(session + 1, 0).into()
+    }
+
+    pub fn xattr_range(ino: u64) -> Range<Key> {
+        Self::xattr(ino, "").into()..Self::xattr(ino + 1, "").into()
+    }
+
     pub fn inode_range(ino_range: Range<u64>) -> Range<Key> {
         Self::inode(ino_range.start).into()..Self::inode(ino_range.end).into()
     }
@@ -63,8 +266,33 @@ impl<'a> ScopedKey<'a> {
             Meta => Self::META,
             Inode(_) => Self::INODE,
             Block { ino: _, block: _ } => Self::BLOCK,
-            FileHandler { ino: _, handler: _ } => Self::HANDLER,
+            Opened { ino: _, session: _ } => Self::OPENED,
             FileIndex { parent: _, name: _ } => Self::INDEX,
+            BlockLink { ino: _, block: _ } => Self::BLOCK_LINK,
+            BlockShare { ino: _, block: _ } => Self::BLOCK_SHARE,
+            Snapshot { name: _ } => Self::SNAPSHOT,
+            Xattr { ino: _, name: _ } => Self::XATTR,
+            Quota { kind: _, id: _ } => Self::QUOTA,
+            Usage(_) => Self::USAGE,
+            Lock(_) => Self::LOCK,
+            Session(_) => Self::SESSION,
+            SessionOpened { session: _, ino: _ } => Self::SESSION_OPENED,
+            Times(_) => Self::TIMES,
+            Inline(_) => Self::INLINE,
+            Atime(_) => Self::ATIME,
+            Delta {
+                ino: _,
+                block: _,
+                seq: _,
+            } => Self::DELTA,
+            TreeTask { root: _, dir: _ } => Self::TREE_TASK,
+            TreeFork { root: _, ino: _ } => Self::TREE_FORK,
+            Staged { ino: _, block: _ } => Self::STAGED,
+            QuotaUsage {
+                kind: _,
+                id: _,
+                shard: _,
+            } => Self::QUOTA_USAGE,
         }
     }
 
@@ -75,8 +303,33 @@ impl<'a> ScopedKey<'a> {
             Meta => 0,
             Inode(_) => size_of::<u64>(),
             Block { ino: _, block: _ } => size_of::<u64>() * 2,
-            FileHandler { ino: _, handler: _ } => size_of::<u64>() * 2,
+            Opened { ino: _, session: _ } => size_of::<u64>() * 2,
             FileIndex { parent: _, name } => size_of::<u64>() + name.len(),
+            BlockLink { ino: _, block: _ } => size_of::<u64>() * 2,
+            BlockShare { ino: _, block: _ } => size_of::<u64>() * 2,
+            Snapshot { name } => name.len(),
+            Xattr { ino: _, name } => size_of::<u64>() + name.len(),
+            Quota { kind: _, id: _ } => size_of::<u8>() + size_of::<u32>(),
+            Usage(_) => size_of::<u64>(),
+            Lock(_) => size_of::<u64>(),
+            Session(_) => size_of::<u64>(),
+            SessionOpened { session: _, ino: _ } => size_of::<u64>() * 2,
+            Times(_) => size_of::<u64>(),
+            Inline(_) => size_of::<u64>(),
+            Atime(_) => size_of::<u64>(),
+            Delta {
+                ino: _,
+                block: _,
+                seq: _,
+            } => size_of::<u64>() * 3,
+            TreeTask { root: _, dir: _ } => size_of::<u64>() * 2,
+            TreeFork { root: _, ino: _ } => size_of::<u64>() * 2,
+            Staged { ino: _, block: _ } => size_of::<u64>() * 2,
+            QuotaUsage {
+                kind: _,
+                id: _,
+                shard: _,
+            } => size_of::<u8>() + size_of::<u32>() + size_of::<u64>(),
         }
     }
 
@@ -95,11 +348,11 @@ impl<'a> ScopedKey<'a> {
                 let block = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
                 Ok(Self::block(ino, block))
             }
-            Self::HANDLER => {
+            Self::OPENED => {
                 let mut arrays = data.array_chunks();
                 let ino = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
-                let handler = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
-                Ok(Self::handler(ino, handler))
+                let session = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                Ok(Self::opened(ino, session))
             }
             Self::INDEX => {
                 let parent =
@@ -109,6 +362,102 @@ impl<'a> ScopedKey<'a> {
                     std::str::from_utf8(&data[size_of::<u64>()..]).map_err(|_| invalid_key())?,
                 ))
             }
+            Self::BLOCK_LINK => {
+                let mut arrays = data.array_chunks();
+                let ino = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                let block = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                Ok(Self::block_link(ino, block))
+            }
+            Self::BLOCK_SHARE => {
+                let mut arrays = data.array_chunks();
+                let ino = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                let block = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                Ok(Self::block_share(ino, block))
+            }
+            Self::SNAPSHOT => Ok(Self::snapshot(
+                std::str::from_utf8(data).map_err(|_| invalid_key())?,
+            )),
+            Self::XATTR => {
+                let ino = u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::xattr(
+                    ino,
+                    std::str::from_utf8(&data[size_of::<u64>()..]).map_err(|_| invalid_key())?,
+                ))
+            }
+            Self::QUOTA => {
+                let (kind, id) = data.split_first().ok_or_else(invalid_key)?;
+                let kind = QuotaKind::from_raw(*kind).ok_or_else(invalid_key)?;
+                let id = u32::from_be_bytes(*id.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::quota(kind, id))
+            }
+            Self::USAGE => {
+                let shard =
+                    u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::usage(shard))
+            }
+            Self::LOCK => {
+                let ino = u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::lock(ino))
+            }
+            Self::SESSION => {
+                let id = u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::session(id))
+            }
+            Self::SESSION_OPENED => {
+                let mut arrays = data.array_chunks();
+                let session = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                let ino = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                Ok(Self::session_opened(session, ino))
+            }
+            Self::TIMES => {
+                let ino = u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::times(ino))
+            }
+            Self::INLINE => {
+                let ino = u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::inline(ino))
+            }
+            Self::ATIME => {
+                let ino = u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::atime(ino))
+            }
+            Self::DELTA => {
+                let mut arrays = data.array_chunks();
+                let ino = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                let block = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                let seq = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                Ok(Self::delta(ino, block, seq))
+            }
+            Self::TREE_TASK => {
+                let mut arrays = data.array_chunks();
+                let root = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                let dir = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                Ok(Self::tree_task(root, dir))
+            }
+            Self::TREE_FORK => {
+                let mut arrays = data.array_chunks();
+                let root = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                let ino = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                Ok(Self::tree_fork(root, ino))
+            }
+            Self::STAGED => {
+                let mut arrays = data.array_chunks();
+                let ino = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                let block = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
+                Ok(Self::staged(ino, block))
+            }
+            Self::QUOTA_USAGE => {
+                let (kind, data) = data.split_first().ok_or_else(invalid_key)?;
+                let kind = QuotaKind::from_raw(*kind).ok_or_else(invalid_key)?;
+                let id = u32::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                let shard = u64::from_be_bytes(
+                    *data[size_of::<u32>()..]
+                        .array_chunks()
+                        .next()
+                        .ok_or_else(invalid_key)?,
+                );
+                Ok(Self::quota_usage(kind, id, shard))
+            }
             _ => Err(invalid_key()),
         }
     }
@@ -122,19 +471,47 @@ impl<'a> From<ScopedKey<'a>> for Key {
         data.push(key.scope());
         match key {
             Meta => (),
-            Inode(ino) => data.extend(ino.to_be_bytes().iter()),
+            Inode(ino) | Usage(ino) | Lock(ino) | Session(ino) | Times(ino) | Inline(ino)
+            | Atime(ino) => data.extend(ino.to_be_bytes().iter()),
             Block { ino, block } => {
                 data.extend(ino.to_be_bytes().iter());
                 data.extend(block.to_be_bytes().iter())
             }
-            FileHandler { ino, handler } => {
+            Opened { ino, session } => {
                 data.extend(ino.to_be_bytes().iter());
-                data.extend(handler.to_be_bytes().iter())
+                data.extend(session.to_be_bytes().iter())
             }
-            FileIndex { parent, name } => {
+            FileIndex { parent, name } | Xattr { ino: parent, name } => {
                 data.extend(parent.to_be_bytes().iter());
                 data.extend(name.as_bytes().iter());
             }
+            BlockLink { ino, block } | BlockShare { ino, block } | Staged { ino, block } => {
+                data.extend(ino.to_be_bytes().iter());
+                data.extend(block.to_be_bytes().iter())
+            }
+            Snapshot { name } => data.extend(name.as_bytes().iter()),
+            Quota { kind, id } => {
+                data.push(kind.raw());
+                data.extend(id.to_be_bytes().iter())
+            }
+            QuotaUsage { kind, id, shard } => {
+                data.push(kind.raw());
+                data.extend(id.to_be_bytes().iter());
+                data.extend(shard.to_be_bytes().iter())
+            }
+            Delta { ino, block, seq } => {
+                data.extend(ino.to_be_bytes().iter());
+                data.extend(block.to_be_bytes().iter());
+                data.extend(seq.to_be_bytes().iter())
+            }
+            SessionOpened { session, ino } => {
+                data.extend(session.to_be_bytes().iter());
+                data.extend(ino.to_be_bytes().iter())
+            }
+            TreeTask { root, dir: ino } | TreeFork { root, ino } => {
+                data.extend(root.to_be_bytes().iter());
+                data.extend(ino.to_be_bytes().iter())
+            }
         }
         data.into()
     }
@@ -0,0 +1,218 @@
+use libc::{F_RDLCK, F_UNLCK, F_WRLCK};
+use serde::{Deserialize, Serialize};
+
+use super::error::{FsError, Result};
+use super::serialize::{deserialize, serialize, ENCODING};
+
+/// A POSIX lock on the bytes from `start` to `end`, both inclusive.
+///
+/// The lock is held by the session of the mount which takes it, and released
+/// once the session expires.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy, Deserialize, Serialize)]
+pub struct LockRange {
+    pub session: u64,
+    pub owner: u64,
+    pub pid: u32,
+    pub typ: i32,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl LockRange {
+    pub const fn new(session: u64, owner: u64, pid: u32, typ: i32, start: u64, end: u64) -> Self {
+        Self {
+            session,
+            owner,
+            pid,
+            typ,
+            start,
+            end,
+        }
+    }
+
+    /// Whether two locks are held by the same owner of the same mount.
+    const fn same_holder(&self, other: &Self) -> bool {
+        self.session == other.session && self.owner == other.owner
+    }
+
+    const fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    /// Whether two ranges of the same owner and type can be merged into one.
+    const fn touches(&self, other: &Self) -> bool {
+        self.start <= other.end.saturating_add(1) && other.start <= self.end.saturating_add(1)
+    }
+}
+
+/// All POSIX locks of an inode, stored apart from the inode.
+///
+/// Ranges of the same owner never overlap, and adjacent ranges of the same type are merged.
+#[derive(Debug, Default, Eq, PartialEq, Hash, Clone, Deserialize, Serialize)]
+pub struct LockTable {
+    pub locks: Vec<LockRange>,
+}
+
+impl LockTable {
+    pub fn is_empty(&self) -> bool {
+        self.locks.is_empty()
+    }
+
+    /// Find a lock of another owner which conflicts with `lock`.
+    pub fn conflict(&self, lock: &LockRange) -> Option<&LockRange> {
+        self.locks.iter().find(|current| {
+            !current.same_holder(lock)
+                && current.overlaps(lock.start, lock.end)
+                && (lock.typ == F_WRLCK || current.typ == F_WRLCK)
+        })
+    }
+
+    /// Lock, relock or unlock a range for an owner, existing ranges of the owner are
+    /// split if they are partly covered. Conflicts should be checked before.
+    pub fn set(&mut self, lock: LockRange) {
+        debug_assert!(matches!(lock.typ, F_RDLCK | F_WRLCK | F_UNLCK));
+        let mut locks = Vec::with_capacity(self.locks.len() + 2);
+        for current in self.locks.drain(..) {
+            if !current.same_holder(&lock) || !current.overlaps(lock.start, lock.end) {
+                locks.push(current);
+                continue;
+            }
+            if current.start < lock.start {
+                locks.push(LockRange {
+                    end: lock.start - 1,
+                    ..current
+                });
+            }
+            if current.end > lock.end {
+                locks.push(LockRange {
+                    start: lock.end + 1,
+                    ..current
+                });
+            }
+        }
+
+        if lock.typ != F_UNLCK {
+            let mut merged = lock;
+            locks.retain(|current| {
+                let mergeable = current.same_holder(&merged)
+                    && current.typ == merged.typ
+                    && current.touches(&merged);
+                if mergeable {
+                    merged.start = merged.start.min(current.start);
+                    merged.end = merged.end.max(current.end);
+                }
+                !mergeable
+            });
+            locks.push(merged);
+        }
+        locks.sort_by_key(|lock| (lock.start, lock.owner));
+        self.locks = locks;
+    }
+
+    /// Release all locks of an owner, return whether anything is released.
+    pub fn remove_owner(&mut self, session: u64, owner: u64) -> bool {
+        let len = self.locks.len();
+        self.locks
+            .retain(|lock| lock.session != session || lock.owner != owner);
+        len != self.locks.len()
+    }
+
+    /// Release all locks held by a session, return whether anything is released.
+    pub fn remove_session(&mut self, session: u64) -> bool {
+        let len = self.locks.len();
+        self.locks.retain(|lock| lock.session != session);
+        len != self.locks.len()
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "lock table",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "lock table",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(owner: u64, typ: i32, start: u64, end: u64) -> LockRange {
+        LockRange::new(1, owner, 0, typ, start, end)
+    }
+
+    fn table(locks: &[LockRange]) -> LockTable {
+        let mut table = LockTable::default();
+        for lock in locks {
+            table.set(*lock);
+        }
+        table
+    }
+
+    #[test]
+    fn split_in_the_middle() {
+        let table = table(&[lock(1, F_WRLCK, 0, 99), lock(1, F_UNLCK, 40, 59)]);
+        assert_eq!(
+            table.locks,
+            vec![lock(1, F_WRLCK, 0, 39), lock(1, F_WRLCK, 60, 99)]
+        );
+    }
+
+    #[test]
+    fn relock_changes_type() {
+        let table = table(&[lock(1, F_RDLCK, 0, 99), lock(1, F_WRLCK, 20, 29)]);
+        assert_eq!(
+            table.locks,
+            vec![
+                lock(1, F_RDLCK, 0, 19),
+                lock(1, F_WRLCK, 20, 29),
+                lock(1, F_RDLCK, 30, 99),
+            ]
+        );
+        assert!(table.conflict(&lock(2, F_RDLCK, 25, 25)).is_some());
+        assert!(table.conflict(&lock(2, F_RDLCK, 50, 50)).is_none());
+        assert!(table.conflict(&lock(2, F_WRLCK, 50, 50)).is_some());
+    }
+
+    #[test]
+    fn merge_adjacent() {
+        let merged = table(&[lock(1, F_RDLCK, 0, 9), lock(1, F_RDLCK, 10, 19)]);
+        assert_eq!(merged.locks, vec![lock(1, F_RDLCK, 0, 19)]);
+
+        let apart = table(&[lock(1, F_RDLCK, 0, 9), lock(1, F_WRLCK, 10, 19)]);
+        assert_eq!(
+            apart.locks,
+            vec![lock(1, F_RDLCK, 0, 9), lock(1, F_WRLCK, 10, 19)]
+        );
+    }
+
+    #[test]
+    fn whole_file() {
+        let end = std::i64::MAX as u64;
+        let table = table(&[lock(1, F_WRLCK, 0, end), lock(1, F_UNLCK, 100, end)]);
+        assert_eq!(table.locks, vec![lock(1, F_WRLCK, 0, 99)]);
+        assert!(table.conflict(&lock(2, F_RDLCK, 99, end)).is_some());
+        assert!(table.conflict(&lock(2, F_WRLCK, 100, end)).is_none());
+    }
+
+    #[test]
+    fn unlock_subrange() {
+        let mut table = table(&[lock(1, F_WRLCK, 10, 19), lock(2, F_RDLCK, 30, 39)]);
+        table.set(lock(1, F_UNLCK, 0, 14));
+        assert_eq!(
+            table.locks,
+            vec![lock(1, F_WRLCK, 15, 19), lock(2, F_RDLCK, 30, 39)]
+        );
+        table.set(lock(1, F_UNLCK, 15, 19));
+        assert_eq!(table.locks, vec![lock(2, F_RDLCK, 30, 39)]);
+        assert!(table.conflict(&lock(1, F_WRLCK, 0, 29)).is_none());
+    }
+}
@@ -7,15 +7,27 @@ use super::serialize::{deserialize, serialize, ENCODING};
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct Meta {
     pub inode_next: u64,
+    /// Capacity of the volume in bytes, unlimited if it's `None`.
+    #[serde(default)]
+    pub capacity: Option<u64>,
+    /// Counter to generate ids of sessions.
+    #[serde(default = "Meta::first_session")]
+    pub session_next: u64,
 }
 
 impl Meta {
     pub const fn new() -> Self {
         Self {
             inode_next: ROOT_INODE,
+            capacity: None,
+            session_next: Self::first_session(),
         }
     }
 
+    const fn first_session() -> u64 {
+        1
+    }
+
     pub fn serialize(&self) -> Result<Vec<u8>> {
         serialize(self).map_err(|err| FsError::Serialize {
             target: "meta",
@@ -38,3 +50,24 @@ impl Default for Meta {
         Self::new()
     }
 }
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_without_capacity() {
+        let meta = Meta::deserialize(br#"{"inode_next":5,"session_next":3}"#).unwrap();
+        assert_eq!(meta.inode_next, 5);
+        assert_eq!(meta.capacity, None);
+        assert_eq!(meta.session_next, 3);
+    }
+
+    #[test]
+    fn decode_without_sessions() {
+        let meta = Meta::deserialize(br#"{"inode_next":5}"#).unwrap();
+        assert_eq!(meta.inode_next, 5);
+        assert_eq!(meta.capacity, None);
+        assert_eq!(meta.session_next, 1);
+    }
+}
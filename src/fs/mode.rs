@@ -1,7 +1,8 @@
 use fuser::FileType;
 
+/// Permission bits of a mode, including the setuid, setgid and sticky bits.
 pub const fn as_file_perm(mode: u32) -> u16 {
-    (mode & !(libc::S_ISUID | libc::S_ISGID) as u32) as _
+    (mode & 0o7777) as _
 }
 
 #[cfg(target_os = "freebsd")]
@@ -0,0 +1,150 @@
+use std::ops::AddAssign;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::{FsError, Result};
+use super::inode::Inode;
+use super::serialize::{deserialize, serialize, ENCODING};
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy, Deserialize, Serialize)]
+pub enum QuotaKind {
+    User,
+    Group,
+    Project,
+}
+
+impl QuotaKind {
+    pub const fn from_raw(kind: u8) -> Option<Self> {
+        use QuotaKind::*;
+
+        match kind {
+            0 => Some(User),
+            1 => Some(Group),
+            2 => Some(Project),
+            _ => None,
+        }
+    }
+
+    pub const fn raw(self) -> u8 {
+        use QuotaKind::*;
+
+        match self {
+            User => 0,
+            Group => 1,
+            Project => 2,
+        }
+    }
+
+    /// Quotas an inode is charged to, an inode without a project is charged to no project.
+    pub fn of(inode: &Inode) -> Vec<(Self, u32)> {
+        let mut quotas = vec![(QuotaKind::User, inode.uid), (QuotaKind::Group, inode.gid)];
+        if inode.project != 0 {
+            quotas.push((QuotaKind::Project, inode.project));
+        }
+        quotas
+    }
+}
+
+/// Usage and limits of a user, group or project.
+///
+/// Usage is tracked only for ids with a quota, a limit of `None` means unlimited.
+/// The usage is kept in shards and summed up when a quota is read, it is always zero in the
+/// stored quota.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy, Deserialize, Serialize)]
+pub struct Quota {
+    pub kind: QuotaKind,
+    pub id: u32,
+    pub bytes: u64,
+    pub inodes: u64,
+    pub bytes_limit: Option<u64>,
+    pub inodes_limit: Option<u64>,
+}
+
+impl Quota {
+    pub const fn new(kind: QuotaKind, id: u32) -> Self {
+        Self {
+            kind,
+            id,
+            bytes: 0,
+            inodes: 0,
+            bytes_limit: None,
+            inodes_limit: None,
+        }
+    }
+
+    /// Whether a change of usage would be checked against any limit.
+    pub fn limits(&self, bytes: i64, inodes: i64) -> bool {
+        (bytes > 0 && self.bytes_limit.is_some()) || (inodes > 0 && self.inodes_limit.is_some())
+    }
+
+    /// Check a change of usage, only an increase is checked against the limits.
+    pub fn check(&self, bytes: i64, inodes: i64) -> Result<()> {
+        let exceed = |used: u64, delta: i64, limit: Option<u64>| {
+            delta > 0 && limit.map_or(false, |limit| used + delta as u64 > limit)
+        };
+        if exceed(self.bytes, bytes, self.bytes_limit)
+            || exceed(self.inodes, inodes, self.inodes_limit)
+        {
+            return Err(FsError::QuotaExceeded {
+                kind: self.kind,
+                id: self.id,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "quota",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "quota",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
+/// A shard of the usage of a quota.
+///
+/// Like the usage of the volume, a shard may be negative and only the sum of all shards is
+/// meaningful.
+#[derive(Debug, Default, Eq, PartialEq, Hash, Clone, Copy, Deserialize, Serialize)]
+pub struct QuotaUsage {
+    pub bytes: i64,
+    pub inodes: i64,
+}
+
+impl QuotaUsage {
+    pub const fn new(bytes: i64, inodes: i64) -> Self {
+        Self { bytes, inodes }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "quota usage",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "quota usage",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
+impl AddAssign for QuotaUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.bytes += other.bytes;
+        self.inodes += other.inodes;
+    }
+}
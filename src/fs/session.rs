@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+use super::error::{FsError, Result};
+use super::serialize::{deserialize, serialize, ENCODING};
+use super::tikv_fs::TiFs;
+
+/// A mount of the volume, kept alive by renewing its lease periodically.
+///
+/// Anything tagged with a session is abandoned once the lease expires or the session is
+/// removed, so a crashed mount is recovered by other mounts.
+///
+/// The deadline is in the physical time of TiKV timestamps in milliseconds, so that all
+/// mounts agree on it whatever their local clocks are.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Session {
+    pub id: u64,
+    pub deadline: u64,
+}
+
+impl Session {
+    pub fn new(id: u64, now: u64) -> Self {
+        Self {
+            id,
+            deadline: now + TiFs::SESSION_TIMEOUT.as_millis() as u64,
+        }
+    }
+
+    pub fn renew(&mut self, now: u64) {
+        self.deadline = now + TiFs::SESSION_TIMEOUT.as_millis() as u64;
+    }
+
+    pub fn expired(&self, now: u64) -> bool {
+        self.deadline <= now
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "session",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "session",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
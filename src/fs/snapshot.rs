@@ -0,0 +1,95 @@
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::{FsError, Result};
+use super::serialize::{deserialize, serialize, ENCODING};
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub name: String,
+    pub source: u64,
+    pub root: u64,
+    pub created: SystemTime,
+}
+
+impl Snapshot {
+    pub fn new(name: String, source: u64, root: u64) -> Self {
+        Self {
+            name,
+            source,
+            root,
+            created: SystemTime::now(),
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "snapshot",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "snapshot",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
+/// A directory queued to be walked by the `Clone` or `Empty` intent of the root of its tree.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct TreeTask {
+    /// Number of entries copied from the start of the directory, for cloning.
+    pub copied: u64,
+}
+
+/// The copy of a file with several links in a tree being cloned, so that its links in the
+/// tree are copied as links of one inode.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct TreeFork {
+    pub ino: u64,
+}
+
+impl TreeTask {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "tree task",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "tree task",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
+impl TreeFork {
+    pub const fn new(ino: u64) -> Self {
+        Self { ino }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "tree fork",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "tree fork",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
@@ -1,28 +1,44 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Debug};
 use std::future::Future;
 use std::matches;
+use std::ops::Range;
 use std::pin::Pin;
-use std::time::{Duration, SystemTime};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::anyhow;
-use async_std::task::sleep;
+use async_std::future::timeout;
+use async_std::sync::Mutex as AsyncMutex;
+use async_std::task::{sleep, spawn, spawn_blocking, JoinHandle};
 use async_trait::async_trait;
 use bytes::Bytes;
 use bytestring::ByteString;
 use fuser::consts::FOPEN_DIRECT_IO;
 use fuser::*;
-use libc::{F_RDLCK, F_UNLCK, F_WRLCK, O_DIRECT, SEEK_CUR, SEEK_END, SEEK_SET};
+use futures::channel::oneshot;
+use libc::{
+    F_RDLCK, F_WRLCK, O_ACCMODE, O_DIRECT, O_RDONLY, O_TRUNC, O_WRONLY, R_OK, SEEK_CUR, SEEK_DATA,
+    SEEK_END, SEEK_HOLE, SEEK_SET, W_OK, X_OK,
+};
+use lru::LruCache;
 use tikv_client::{Config, TransactionClient};
-use tracing::{debug, info, instrument, trace, warn};
+use tracing::{debug, error, info, instrument, trace, warn};
 
+use super::credentials::Credentials;
 use super::dir::Directory;
 use super::error::{FsError, Result};
-use super::inode::Inode;
-use super::key::{ScopedKey, ROOT_INODE};
-use super::mode::make_mode;
+use super::file_handler::FileHandler;
+use super::inode::AtimePolicy;
+use super::key::{ROOT_INODE, SNAPSHOTS_INODE};
+use super::lock::LockRange;
+use super::mode::{as_file_perm, make_mode};
 use super::reply::get_time;
-use super::reply::{Attr, Create, Data, Dir, DirItem, Entry, Lseek, Open, StatFs, Write};
+use super::reply::{Attr, Create, Data, Dir, DirItem, Entry, Lseek, Open, StatFs, Write, Xattr};
+use super::snapshot::Snapshot;
 use super::transaction::Txn;
+use super::usage::Space;
 use super::{async_fs::AsyncFileSystem, reply::Lock};
 use crate::MountOption;
 
@@ -30,7 +46,29 @@ pub struct TiFs {
     pub pd_endpoints: Vec<String>,
     pub config: Config,
     pub client: TransactionClient,
+    /// A client of read-only transactions, so that they have connections of their own.
+    reader: TransactionClient,
     pub direct_io: bool,
+    pub atime: AtimePolicy,
+    inodes: Mutex<Vec<Range<u64>>>,
+    session: Arc<AtomicU64>,
+    /// Local deadline of the lease of the session, which never passes the one in TiKV as it
+    /// is counted from before each renewal.
+    lease: Arc<Mutex<Instant>>,
+    /// Background tasks of this mount, cancelled on unmounting.
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    /// Space of the volume refreshed by a background task, `None` before the first refresh.
+    space: Arc<Mutex<Option<Space>>>,
+    /// Blocks whose deltas are to be folded by the compactor.
+    compactions: Arc<Mutex<HashSet<(u64, u64)>>>,
+    waiters: Mutex<HashMap<u64, Vec<oneshot::Sender<()>>>>,
+    next_fh: AtomicU64,
+    handlers: Mutex<HashMap<u64, FileHandler>>,
+    /// Numbers of file handlers opened by this mount for each inode and the session recording
+    /// it, each locked across the transaction recording the inode as opened or closed.
+    opened: Mutex<HashMap<(u64, u64), Arc<AsyncMutex<u64>>>>,
+    /// Supplementary groups of recent callers by pid, with the time they were read.
+    groups: Mutex<LruCache<u32, (Instant, Arc<[u32]>)>>,
 }
 
 type BoxedFuture<'a, T> = Pin<Box<dyn 'a + Send + Future<Output = Result<T>>>>;
@@ -42,7 +80,27 @@ impl TiFs {
     pub const DIR_CACHE: usize = 1 << 24;
     pub const INODE_CACHE: usize = 1 << 24;
     pub const MAX_NAME_LEN: u32 = 1 << 8;
+    pub const XATTR_NAME_MAX: usize = 255;
+    pub const XATTR_SIZE_MAX: usize = 1 << 16;
     pub const INLINE_DATA_THRESHOLD: u64 = 1 << 12;
+    pub const SNAPSHOTS_DIR: &'static str = ".snapshots";
+    pub const MAX_TXN_BLOCKS: u64 = 1 << 6;
+    pub const MAX_TXN_ENTRIES: u64 = 1 << 8;
+    pub const INTENT_TIMEOUT: Duration = Duration::from_secs(30);
+    pub const BUSY_DELAY: Duration = Duration::from_millis(10);
+    pub const INODE_LEASE: u64 = 1 << 10;
+    pub const USAGE_SHARDS: u64 = 1 << 6;
+    pub const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+    pub const SESSION_HEARTBEAT: Duration = Duration::from_secs(10);
+    pub const LOCK_WAIT_MAX: Duration = Duration::from_secs(1);
+    pub const DELTA_THRESHOLD: u64 = 1 << 12;
+    pub const COMPACT_DELTAS: u64 = 1 << 3;
+    pub const MAX_BLOCK_DELTAS: u64 = 1 << 5;
+    pub const COMPACT_INTERVAL: Duration = Duration::from_secs(1);
+    pub const MTIME_GRANULARITY: Duration = Duration::from_secs(1);
+    pub const GROUPS_CACHE: usize = 1 << 10;
+    pub const GROUPS_TTL: Duration = Duration::from_secs(1);
+    pub const SPACE_REFRESH: Duration = Duration::from_secs(1);
 
     #[instrument]
     pub async fn construct<S>(
@@ -56,18 +114,231 @@ impl TiFs {
         let client = TransactionClient::new_with_config(pd_endpoints.clone(), cfg.clone())
             .await
             .map_err(|err| anyhow!("{}", err))?;
+        let reader = TransactionClient::new_with_config(pd_endpoints.clone(), cfg.clone())
+            .await
+            .map_err(|err| anyhow!("{}", err))?;
         info!("connected to pd endpoints: {:?}", pd_endpoints);
         Ok(TiFs {
             client,
+            reader,
             pd_endpoints: pd_endpoints.clone().into_iter().map(Into::into).collect(),
             config: cfg,
             direct_io: options
                 .iter()
                 .find(|option| matches!(option, MountOption::DirectIO))
                 .is_some(),
+            atime: options
+                .iter()
+                .fold(AtimePolicy::default(), |policy, option| match option {
+                    MountOption::Noatime => AtimePolicy::NoAtime,
+                    MountOption::Relatime => AtimePolicy::RelAtime,
+                    MountOption::Strictatime => AtimePolicy::StrictAtime,
+                    _ => policy,
+                }),
+            inodes: Mutex::new(Vec::new()),
+            session: Arc::new(AtomicU64::new(0)),
+            lease: Arc::new(Mutex::new(Instant::now())),
+            tasks: Mutex::new(Vec::new()),
+            space: Arc::new(Mutex::new(None)),
+            compactions: Arc::new(Mutex::new(HashSet::new())),
+            waiters: Mutex::new(HashMap::new()),
+            next_fh: AtomicU64::new(1),
+            handlers: Mutex::new(HashMap::new()),
+            opened: Mutex::new(HashMap::new()),
+            groups: Mutex::new(LruCache::new(Self::GROUPS_CACHE)),
         })
     }
 
+    /// Resolve supplementary groups of the caller before any transaction, which are read from
+    /// procfs off the executor and cached for `GROUPS_TTL`.
+    async fn resolve(&self, cred: &Credentials) -> Credentials {
+        if let Some((read, groups)) = self.groups.lock().unwrap().get(&cred.pid) {
+            if read.elapsed() < Self::GROUPS_TTL {
+                return cred.with_groups(groups.clone());
+            }
+        }
+        let pid = cred.pid;
+        let groups: Arc<[u32]> = spawn_blocking(move || Credentials::read_groups(pid))
+            .await
+            .into();
+        self.groups
+            .lock()
+            .unwrap()
+            .put(pid, (Instant::now(), groups.clone()));
+        cred.with_groups(groups)
+    }
+
+    /// Id of the session of this mount.
+    fn session(&self) -> u64 {
+        self.session.load(Ordering::Acquire)
+    }
+
+    /// Refuse lock operations while the local lease of the session is expired, as other mounts
+    /// may have released the locks of the session until it is renewed or registered again.
+    fn check_lease(&self) -> Result<()> {
+        if *self.lease.lock().unwrap() <= Instant::now() {
+            return Err(FsError::LeaseExpired {
+                session: self.session(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Register a waiter for locks on an inode, which is woken once a lock is released
+    /// by this mount. Locks released by other mounts are only found by polling.
+    fn watch_locks(&self, ino: u64) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        let mut waiters = self.waiters.lock().unwrap();
+        let senders = waiters.entry(ino).or_default();
+        senders.retain(|sender| !sender.is_canceled());
+        senders.push(sender);
+        receiver
+    }
+
+    /// Wake all waiters for locks on an inode.
+    fn notify_locks(&self, ino: u64) {
+        let senders = self.waiters.lock().unwrap().remove(&ino);
+        for sender in senders.into_iter().flatten() {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Renew the session of this mount periodically with a client of its own,
+    /// and clean up sessions of other mounts which have expired.
+    async fn heartbeat(
+        client: TransactionClient,
+        session: Arc<AtomicU64>,
+        lease: Arc<Mutex<Instant>>,
+    ) {
+        loop {
+            sleep(Self::SESSION_HEARTBEAT).await;
+            let id = session.load(Ordering::Acquire);
+            let renewing = Instant::now();
+            match Self::renew_session(&client, id).await {
+                Ok(renewed) if renewed != id => {
+                    warn!(
+                        "session({}) expired, its locks and file handlers are lost and session({}) is registered",
+                        id, renewed
+                    );
+                    session.store(renewed, Ordering::Release);
+                    *lease.lock().unwrap() = renewing + Self::SESSION_TIMEOUT;
+                }
+                Ok(_) => {
+                    trace!("session({}) renewed", id);
+                    *lease.lock().unwrap() = renewing + Self::SESSION_TIMEOUT;
+                }
+                Err(err) => error!("fail to renew session({}): {}", id, err),
+            }
+            if let Err(err) = Self::reap_sessions(&client).await {
+                error!("fail to reap expired sessions: {}", err);
+            }
+        }
+    }
+
+    /// Fold the deltas of blocks queued by this mount periodically with a client of its own.
+    /// Blocks failing with a retriable error are queued again for the next round.
+    async fn compactor(client: TransactionClient, queue: Arc<Mutex<HashSet<(u64, u64)>>>) {
+        loop {
+            sleep(Self::COMPACT_INTERVAL).await;
+            let blocks: Vec<_> = queue.lock().unwrap().drain().collect();
+            for (ino, block) in blocks {
+                match Self::compact_block(&client, ino, block).await {
+                    Ok(()) => (),
+                    Err(FsError::KeyError(_))
+                    | Err(FsError::PendingIntent { ino: _ })
+                    | Err(FsError::InodeBusy { ino: _ }) => {
+                        trace!("retry compaction of block(<{}>[{}]) later", ino, block);
+                        queue.lock().unwrap().insert((ino, block));
+                    }
+                    Err(err) => error!("fail to compact block(<{}>[{}]): {}", ino, block, err),
+                }
+            }
+        }
+    }
+
+    /// Refresh the cached space of the volume periodically with a client of its own.
+    async fn refresher(client: TransactionClient, space: Arc<Mutex<Option<Space>>>) {
+        loop {
+            let result = match Txn::begin_snapshot(&client).await {
+                Ok(txn) => txn.read_space().await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(refreshed) => *space.lock().unwrap() = Some(refreshed),
+                Err(err) => error!("fail to refresh space of the volume: {}", err),
+            }
+            sleep(Self::SPACE_REFRESH).await;
+        }
+    }
+
+    /// Count blocks allocated by a committed transaction in the cached space.
+    fn count_allocated(&self, txn: &mut Txn) {
+        let blocks = txn.take_allocated();
+        if let Some(space) = self.space.lock().unwrap().as_mut() {
+            space.blocks += blocks;
+        }
+    }
+
+    async fn compact_block(client: &TransactionClient, ino: u64, block: u64) -> Result<()> {
+        let mut txn = Txn::begin_optimistic(client).await?;
+        let result = txn.compact_block(ino, block).await;
+        Self::finish(&mut txn, result).await
+    }
+
+    /// Commit the transaction if the result is ok, otherwise roll it back.
+    async fn finish<T>(txn: &mut Txn, result: Result<T>) -> Result<T> {
+        match result {
+            Ok(v) => {
+                txn.commit().await?;
+                Ok(v)
+            }
+            Err(err) => {
+                txn.rollback().await?;
+                Err(err)
+            }
+        }
+    }
+
+    async fn renew_session(client: &TransactionClient, id: u64) -> Result<u64> {
+        let now = Txn::clock(client).await?;
+        let mut txn = Txn::begin_optimistic(client).await?;
+        let result = txn.renew_session(id, now).await;
+        Ok(Self::finish(&mut txn, result).await?.id)
+    }
+
+    /// Close file handlers of expired sessions, so that files unlinked while open by a
+    /// crashed mount are reclaimed. Several mounts may race on it, the losers retry later.
+    async fn reap_sessions(client: &TransactionClient) -> Result<()> {
+        let now = Txn::clock(client).await?;
+        let txn = Txn::begin_snapshot(client).await?;
+        for session in txn
+            .list_sessions()
+            .await?
+            .into_iter()
+            .filter(|session| session.expired(now))
+        {
+            info!("reap expired session({})", session.id);
+            loop {
+                let mut txn = Txn::begin_optimistic(client).await?;
+                let result = txn.reap_session(session.id, now).await;
+                let removed = Self::finish(&mut txn, result).await?;
+                for ino in txn.take_intents() {
+                    loop {
+                        let mut txn = Txn::begin_optimistic(client).await?;
+                        let result = txn.step_intent(ino).await;
+                        if Self::finish(&mut txn, result).await? {
+                            break;
+                        }
+                    }
+                }
+                if removed {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
     async fn process_txn<F, T>(&self, txn: &mut Txn, f: F) -> Result<T>
     where
         T: 'static + Send,
@@ -93,7 +364,101 @@ impl TiFs {
         F: for<'a> FnOnce(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
     {
         let mut txn = Txn::begin_optimistic(&self.client).await?;
-        self.process_txn(&mut txn, f).await
+        if let Some(inodes) = self.inodes.lock().unwrap().pop() {
+            txn.lend_inodes(inodes);
+        }
+        if let Some(space) = *self.space.lock().unwrap() {
+            txn.lend_space(space);
+        }
+        let result = self.process_txn(&mut txn, f).await;
+        if let Some(inodes) = txn.take_inodes(result.is_ok()) {
+            self.inodes.lock().unwrap().push(inodes);
+        }
+        let value = result?;
+        self.count_allocated(&mut txn);
+        self.compactions
+            .lock()
+            .unwrap()
+            .extend(txn.take_compactions());
+        for ino in txn.take_intents() {
+            self.complete_intent(ino).await?;
+        }
+        Ok(value)
+    }
+
+    /// Apply the pending intent of an inode step by step, each in its own transaction.
+    ///
+    /// Intents set by the steps, and pending intents of other inodes they run into, are
+    /// completed before going on.
+    async fn complete_intent(&self, ino: u64) -> Result<()> {
+        let mut pending = vec![ino];
+        while let Some(&ino) = pending.last() {
+            let mut txn = Txn::begin_optimistic(&self.client).await?;
+            let result = self
+                .process_txn(&mut txn, move |_, txn| Box::pin(txn.step_intent(ino)))
+                .await;
+            if result.is_ok() {
+                self.count_allocated(&mut txn);
+            }
+            match result {
+                Ok(done) => {
+                    if done {
+                        pending.pop();
+                    }
+                    for ino in txn.take_intents() {
+                        if !pending.contains(&ino) {
+                            pending.push(ino);
+                        }
+                    }
+                }
+                Err(FsError::KeyError(err)) => {
+                    trace!("retry intent because of a key error({})", err);
+                }
+                Err(FsError::PendingIntent { ino }) if !pending.contains(&ino) => {
+                    trace!("complete the intent of inode({}) on the way", ino);
+                    pending.push(ino);
+                }
+                Err(FsError::InodeBusy { ino }) => {
+                    trace!("retry intent because inode({}) is busy", ino);
+                    sleep(Self::BUSY_DELAY).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a read-only transaction on a snapshot, which is never committed and never conflicts.
+    async fn with_snapshot<F, T>(&self, f: F) -> Result<T>
+    where
+        T: 'static + Send,
+        F: for<'a> FnOnce(&'a TiFs, &'a Txn) -> BoxedFuture<'a, T>,
+    {
+        let txn = Txn::begin_snapshot(&self.reader).await?;
+        f(self, &txn).await
+    }
+
+    /// Handle an error of a transaction, return it back if the transaction cannot be retried.
+    async fn recover(&self, err: FsError, delay: Option<Duration>) -> Result<()> {
+        match err {
+            FsError::KeyError(err) => {
+                trace!("spin because of a key error({})", err);
+                if let Some(time) = delay {
+                    sleep(time).await;
+                }
+                Ok(())
+            }
+            FsError::PendingIntent { ino } => {
+                trace!("spin after completing the intent of inode({})", ino);
+                self.complete_intent(ino).await
+            }
+            FsError::InodeBusy { ino } => {
+                trace!("spin because inode({}) is busy", ino);
+                sleep(Self::BUSY_DELAY).await;
+                Ok(())
+            }
+            err => Err(err),
+        }
     }
 
     async fn spin<F, T>(&self, delay: Option<Duration>, mut f: F) -> Result<T>
@@ -104,13 +469,20 @@ impl TiFs {
         loop {
             match self.with_optimistic(&mut f).await {
                 Ok(v) => break Ok(v),
-                Err(FsError::KeyError(err)) => {
-                    trace!("spin because of a key error({})", err);
-                    if let Some(time) = delay {
-                        sleep(time).await;
-                    }
-                }
-                Err(err) => break Err(err),
+                Err(err) => self.recover(err, delay).await?,
+            }
+        }
+    }
+
+    async fn spin_read_only<F, T>(&self, mut f: F) -> Result<T>
+    where
+        T: 'static + Send,
+        F: for<'a> FnMut(&'a TiFs, &'a Txn) -> BoxedFuture<'a, T>,
+    {
+        loop {
+            match self.with_snapshot(&mut f).await {
+                Ok(v) => break Ok(v),
+                Err(err) => self.recover(err, None).await?,
             }
         }
     }
@@ -123,64 +495,254 @@ impl TiFs {
         self.spin(None, f).await
     }
 
+    /// Read a directory, its atime is updated like reading a file.
     async fn read_dir(&self, ino: u64) -> Result<Directory> {
-        self.spin_no_delay(move |_, txn| Box::pin(txn.read_dir(ino)))
+        let (stale_atime, dir) = self
+            .spin_read_only(move |fs, txn| {
+                Box::pin(async move {
+                    let inode = txn.read_inode(ino).await?;
+                    let dir = txn.read_dir(ino).await?;
+                    Ok((fs.atime.need_update(&inode), dir))
+                })
+            })
+            .await?;
+        if stale_atime {
+            self.update_atime(ino).await?;
+        }
+        Ok(dir)
+    }
+
+    async fn read_snapshots(&self) -> Result<Vec<Snapshot>> {
+        self.spin_read_only(move |_, txn| Box::pin(txn.list_snapshots()))
             .await
     }
 
+    fn snapshots_attr() -> FileAttr {
+        FileAttr {
+            ino: SNAPSHOTS_INODE,
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::now(),
+            mtime: SystemTime::now(),
+            ctime: SystemTime::now(),
+            crtime: SystemTime::now(),
+            kind: FileType::Directory,
+            perm: 0o555,
+            nlink: 2,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: Self::BLOCK_SIZE as u32,
+            padding: 0,
+            flags: 0,
+        }
+    }
+
     async fn read_inode(&self, ino: u64) -> Result<FileAttr> {
+        if ino == SNAPSHOTS_INODE {
+            return Ok(Self::snapshots_attr());
+        }
         let ino = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.read_inode(ino)))
+            .spin_read_only(move |_, txn| Box::pin(txn.read_inode(ino)))
             .await?;
         Ok(ino.file_attr)
     }
 
-    async fn setlkw(&self, ino: u64, lock_owner: u64, typ: i32) -> Result<bool> {
-        loop {
-            let res = self
-                .spin_no_delay(move |_, txn| {
-                    Box::pin(async move {
-                        let mut inode = txn.read_inode(ino).await?;
-                        match typ {
-                            F_WRLCK => {
-                                if inode.lock_state.owner_set.len() > 1 {
-                                    return Ok(false);
-                                }
-                                if inode.lock_state.owner_set.is_empty() {
-                                    inode.lock_state.lk_type = F_WRLCK;
-                                    inode.lock_state.owner_set.insert(lock_owner);
-                                    txn.save_inode(&inode).await?;
-                                    return Ok(true);
-                                }
-                                if inode.lock_state.owner_set.get(&lock_owner) == Some(&lock_owner)
-                                {
-                                    inode.lock_state.lk_type = F_WRLCK;
-                                    txn.save_inode(&inode).await?;
-                                    return Ok(true);
-                                }
-                                Err(FsError::InvalidLock)
-                            }
-                            F_RDLCK => {
-                                if inode.lock_state.lk_type == F_WRLCK {
-                                    return Ok(false);
-                                } else {
-                                    inode.lock_state.lk_type = F_RDLCK;
-                                    inode.lock_state.owner_set.insert(lock_owner);
-                                    txn.save_inode(&inode).await?;
-                                    return Ok(true);
-                                }
-                            }
-                            _ => return Err(FsError::InvalidLock),
-                        }
-                    })
+    /// Write data in several transactions, each of which stages at most `MAX_TXN_BLOCKS` blocks.
+    /// The file is changed only if the write is finished, otherwise the staged blocks are dropped.
+    async fn write_chunked(
+        &self,
+        ino: u64,
+        fh: u64,
+        handler: FileHandler,
+        offset: i64,
+        data: Bytes,
+    ) -> Result<usize> {
+        let size = data.len();
+        let start = self
+            .spin_no_delay(move |_, txn| {
+                Box::pin(async move {
+                    txn.begin_write(ino, fh, &handler, offset, size as u64)
+                        .await
                 })
-                .await?;
-            if res {
-                break;
+            })
+            .await?;
+
+        if let Err(err) = self.write_staged(ino, start, data).await {
+            if let Err(abort_err) = self
+                .spin_no_delay(move |_, txn| Box::pin(txn.abort_write(ino)))
+                .await
+            {
+                error!("fail to abort the write of inode({}): {}", ino, abort_err);
             }
+            return Err(err);
+        }
+        Ok(size)
+    }
+
+    /// Stage chunks of a write begun at `start`, and finish it.
+    async fn write_staged(&self, ino: u64, start: u64, data: Bytes) -> Result<()> {
+        let size = data.len();
+        let chunk_size = (Self::MAX_TXN_BLOCKS * Self::BLOCK_SIZE) as usize;
+        for chunk_start in (0..size).step_by(chunk_size) {
+            let chunk = data.slice(chunk_start..size.min(chunk_start + chunk_size));
+            let chunk_offset = start + chunk_start as u64;
+            self.spin_no_delay(move |_, txn| {
+                Box::pin(txn.write_chunk(ino, chunk_offset, chunk.clone()))
+            })
+            .await?;
+        }
+
+        self.spin_no_delay(move |_, txn| Box::pin(txn.finish_write(ino, start + size as u64)))
+            .await
+    }
+
+    async fn update_atime(&self, ino: u64) -> Result<()> {
+        self.spin_no_delay(move |fs, txn| Box::pin(txn.update_atime(ino, fs.atime)))
+            .await
+    }
+
+    /// Reply the size of data if `size` is 0, or the data if it fits in the buffer.
+    fn reply_xattr(data: Vec<u8>, size: u32) -> Result<Xattr> {
+        if size == 0 {
+            Ok(Xattr::size(data.len() as u32))
+        } else if data.len() > size as usize {
+            Err(FsError::OutOfRange {
+                size: data.len(),
+                limit: size,
+            })
+        } else {
+            Ok(Xattr::data(data))
+        }
+    }
+
+    /// Open a file handler, checking permissions of the caller if there is one.
+    async fn open_handler(&self, cred: Option<Credentials>, ino: u64, flags: i32) -> Result<Open> {
+        let mask = Self::open_mask(flags);
+        self.spin_read_only(move |_, txn| {
+            let cred = cred.clone();
+            Box::pin(async move {
+                match cred {
+                    Some(cred) => txn.check_access(ino, &cred, mask).await,
+                    None if mask & W_OK != 0 => Txn::check_writable(&txn.read_inode(ino).await?),
+                    None => Ok(()),
+                }
+            })
+        })
+        .await?;
+        let handler = FileHandler::new(ino, 0, flags, self.session());
+        self.add_opened(&handler, flags & O_TRUNC != 0 && handler.writable())
+            .await?;
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.handlers.lock().unwrap().insert(fh, handler);
+
+        let mut open_flags = 0;
+        if self.direct_io || flags & O_DIRECT != 0 {
+            open_flags |= FOPEN_DIRECT_IO;
         }
 
-        Ok(true)
+        Ok(Open::new(fh, open_flags))
+    }
+
+    fn read_fh(&self, ino: u64, fh: u64) -> Result<FileHandler> {
+        match self.handlers.lock().unwrap().get(&fh) {
+            Some(handler) if handler.ino == ino => Ok(*handler),
+            _ => Err(FsError::FhNotFound { ino, fh }),
+        }
+    }
+
+    /// The counter of file handlers of an inode in a session, so that only opens and closes
+    /// of the same inode wait for each other.
+    fn opened_counter(&self, ino: u64, session: u64) -> Arc<AsyncMutex<u64>> {
+        self.opened
+            .lock()
+            .unwrap()
+            .entry((ino, session))
+            .or_insert_with(|| Arc::new(AsyncMutex::new(0)))
+            .clone()
+    }
+
+    /// Drop the counter of an inode if it is zero and nobody else holds it.
+    fn forget_opened(&self, ino: u64, session: u64, counter: &Arc<AsyncMutex<u64>>) {
+        let mut opened = self.opened.lock().unwrap();
+        // counters are only cloned under the lock of the map, so nobody can take it meanwhile
+        if Arc::strong_count(counter) == 2 && counter.try_lock().map_or(false, |count| *count == 0)
+        {
+            opened.remove(&(ino, session));
+        }
+    }
+
+    /// Count a file handler opened by this mount. The inode is recorded as opened by the
+    /// session of the handler only on the first one, and truncated in the same transaction
+    /// if needed.
+    async fn add_opened(&self, handler: &FileHandler, truncate: bool) -> Result<()> {
+        let (ino, session) = (handler.ino, handler.session);
+        let counter = self.opened_counter(ino, session);
+        let mut count = counter.lock().await;
+        let first = *count == 0;
+        let result = if first || truncate {
+            self.spin_no_delay(move |_, txn| {
+                Box::pin(async move {
+                    let mut inode = txn.read_inode(ino).await?;
+                    if first {
+                        txn.open(ino, session).await?;
+                    }
+                    if truncate && inode.kind == FileType::RegularFile {
+                        txn.truncate(&mut inode, 0).await?;
+                        txn.save_inode(&inode).await?;
+                    }
+                    Ok(())
+                })
+            })
+            .await
+        } else {
+            Ok(())
+        };
+        if result.is_ok() {
+            *count += 1;
+        }
+        drop(count);
+        self.forget_opened(ino, session, &counter);
+        result
+    }
+
+    /// Uncount a file handler, the record of its session is removed with the last one.
+    ///
+    /// It's closed under the session which opened it, as the session of the mount may have
+    /// been registered again since then.
+    async fn remove_opened(&self, handler: &FileHandler) -> Result<()> {
+        let (ino, session) = (handler.ino, handler.session);
+        let counter = self.opened_counter(ino, session);
+        let mut count = counter.lock().await;
+        let current = *count;
+        let result = match current {
+            0 => Ok(()),
+            1 => self
+                .spin_no_delay(move |_, txn| Box::pin(txn.close(ino, session)))
+                .await
+                .map(|()| *count = 0),
+            _ => {
+                *count -= 1;
+                Ok(())
+            }
+        };
+        drop(count);
+        self.forget_opened(ino, session, &counter);
+        result
+    }
+
+    /// Permissions required to open a file with `flags`.
+    fn open_mask(flags: i32) -> i32 {
+        let mask = match flags & O_ACCMODE {
+            O_RDONLY => R_OK,
+            O_WRONLY => W_OK,
+            _ => R_OK | W_OK,
+        };
+        if flags & O_TRUNC != 0 {
+            mask | W_OK
+        } else {
+            mask
+        }
     }
 
     fn check_file_name(name: &str) -> Result<()> {
@@ -192,6 +754,22 @@ impl TiFs {
             })
         }
     }
+
+    /// Reject changing `.snapshots` in the root, whose entry would be shadowed by the
+    /// snapshots directory; it exists already if `create` is set, or is read-only otherwise.
+    fn check_snapshots_dir(parent: u64, name: &str, create: bool) -> Result<()> {
+        if parent != ROOT_INODE || name != Self::SNAPSHOTS_DIR {
+            Ok(())
+        } else if create {
+            Err(FsError::FileExist {
+                file: name.to_string(),
+            })
+        } else {
+            Err(FsError::ReadOnly {
+                ino: SNAPSHOTS_INODE,
+            })
+        }
+    }
 }
 
 impl Debug for TiFs {
@@ -204,12 +782,21 @@ impl Debug for TiFs {
 impl AsyncFileSystem for TiFs {
     #[tracing::instrument]
     async fn init(&self, gid: u32, uid: u32, config: &mut KernelConfig) -> Result<()> {
-        // config
-        //     .add_capabilities(fuser::consts::FUSE_POSIX_LOCKS)
-        //     .expect("kernel config failed to add cap_fuse FUSE_POSIX_LOCKS");
+        config
+            .add_capabilities(fuser::consts::FUSE_POSIX_LOCKS)
+            .expect("kernel config failed to add cap_fuse FUSE_POSIX_LOCKS");
         config
             .add_capabilities(fuser::consts::FUSE_FLOCK_LOCKS)
             .expect("kernel config failed to add cap_fuse FUSE_CAP_FLOCK_LOCKS");
+        config
+            .add_capabilities(fuser::consts::FUSE_ATOMIC_O_TRUNC)
+            .expect("kernel config failed to add cap_fuse FUSE_ATOMIC_O_TRUNC");
+        config
+            .add_capabilities(fuser::consts::FUSE_POSIX_ACL)
+            .expect("kernel config failed to add cap_fuse FUSE_POSIX_ACL");
+        config
+            .add_capabilities(fuser::consts::FUSE_EXPORT_SUPPORT)
+            .expect("kernel config failed to add cap_fuse FUSE_EXPORT_SUPPORT");
 
         self.spin_no_delay(move |fs, txn| {
             Box::pin(async move {
@@ -220,9 +807,9 @@ impl AsyncFileSystem for TiFs {
                         .mkdir(
                             0,
                             Default::default(),
-                            make_mode(FileType::Directory, 0o777),
-                            gid,
-                            uid,
+                            0o777,
+                            &Credentials::new(uid, gid, 0),
+                            0,
                         )
                         .await?;
                     debug!("make root directory {:?}", &attr);
@@ -232,30 +819,96 @@ impl AsyncFileSystem for TiFs {
                 }
             })
         })
-        .await
+        .await?;
+
+        let registering = Instant::now();
+        let now = Txn::clock(&self.client).await?;
+        let session = self
+            .spin_no_delay(move |_, txn| Box::pin(txn.new_session(now)))
+            .await?;
+        info!("mounted as session({})", session.id);
+        self.session.store(session.id, Ordering::Release);
+        *self.lease.lock().unwrap() = registering + Self::SESSION_TIMEOUT;
+        let client =
+            TransactionClient::new_with_config(self.pd_endpoints.clone(), self.config.clone())
+                .await?;
+        let compactor_client =
+            TransactionClient::new_with_config(self.pd_endpoints.clone(), self.config.clone())
+                .await?;
+        let refresher_client =
+            TransactionClient::new_with_config(self.pd_endpoints.clone(), self.config.clone())
+                .await?;
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.push(spawn(Self::heartbeat(
+            client,
+            self.session.clone(),
+            self.lease.clone(),
+        )));
+        tasks.push(spawn(Self::compactor(
+            compactor_client,
+            self.compactions.clone(),
+        )));
+        tasks.push(spawn(Self::refresher(refresher_client, self.space.clone())));
+        Ok(())
+    }
+
+    #[tracing::instrument]
+    async fn destroy(&self) {
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for task in tasks {
+            task.cancel().await;
+        }
+        let id = self.session();
+        if let Err(err) = self
+            .spin_no_delay(move |_, txn| Box::pin(txn.remove_session(id)))
+            .await
+        {
+            error!("fail to remove session({}): {}", id, err);
+        }
     }
 
     #[tracing::instrument]
-    async fn lookup(&self, parent: u64, name: ByteString) -> Result<Entry> {
+    async fn lookup(&self, cred: &Credentials, parent: u64, name: ByteString) -> Result<Entry> {
         Self::check_file_name(&name)?;
-        self.spin_no_delay(move |_, txn| {
+        if (parent == ROOT_INODE && &*name == Self::SNAPSHOTS_DIR)
+            || (parent == SNAPSHOTS_INODE && &*name == ".")
+        {
+            return Ok(Entry::new(Self::snapshots_attr(), 0));
+        }
+        let cred = self.resolve(cred).await;
+        self.spin_read_only(move |_, txn| {
+            let cred = cred.clone();
             let name = name.clone();
             Box::pin(async move {
-                let ino = txn.lookup(parent, name).await?;
-                Ok(Entry::new(txn.read_inode(ino).await?.into(), 0))
+                let ino = if parent == SNAPSHOTS_INODE {
+                    match &*name {
+                        ".." => ROOT_INODE,
+                        _ => txn.read_snapshot(name).await?.root,
+                    }
+                } else {
+                    txn.check_access(parent, &cred, X_OK).await?;
+                    txn.lookup(parent, name).await?
+                };
+                // `..` of a snapshot root is the snapshots directory
+                if ino == SNAPSHOTS_INODE {
+                    return Ok(Entry::new(Self::snapshots_attr(), 0));
+                }
+                let inode = txn.read_inode(ino).await?;
+                Ok(Entry::new(inode.file_attr, inode.generation))
             })
         })
         .await
     }
 
     #[tracing::instrument]
-    async fn getattr(&self, ino: u64) -> Result<Attr> {
+    async fn getattr(&self, _cred: &Credentials, ino: u64) -> Result<Attr> {
         Ok(Attr::new(self.read_inode(ino).await?))
     }
 
     #[tracing::instrument]
     async fn setattr(
         &self,
+        cred: &Credentials,
         ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
@@ -270,27 +923,38 @@ impl AsyncFileSystem for TiFs {
         bkuptime: Option<SystemTime>,
         flags: Option<u32>,
     ) -> Result<Attr> {
+        if ino == SNAPSHOTS_INODE {
+            return Err(FsError::ReadOnly { ino });
+        }
+        let cred = self.resolve(cred).await;
+        let handler = fh
+            .map(|fh| self.read_fh(ino, fh).map(|handler| (fh, handler)))
+            .transpose()?;
         self.spin_no_delay(move |_, txn| {
+            let cred = cred.clone();
             Box::pin(async move {
-                // TODO: how to deal with fh, chgtime, bkuptime?
+                // TODO: how to deal with chgtime, bkuptime?
                 let mut attr = txn.read_inode(ino).await?;
-                attr.perm = match mode {
-                    Some(m) => m as _,
-                    None => attr.perm,
-                };
-                attr.uid = uid.unwrap_or(attr.uid);
-                attr.gid = gid.unwrap_or(attr.gid);
-                attr.set_size(size.unwrap_or(attr.size));
-                attr.atime = match atime {
-                    None => attr.atime,
-                    Some(TimeOrNow::SpecificTime(t)) => t,
-                    Some(TimeOrNow::Now) => SystemTime::now(),
-                };
-                attr.mtime = match mtime {
-                    Some(TimeOrNow::SpecificTime(t)) => t,
-                    Some(TimeOrNow::Now) | None => SystemTime::now(),
+                Txn::check_writable(&attr)?;
+                txn.check_setattr(&attr, &cred, uid, gid, size, handler, atime, mtime)
+                    .await?;
+                if let Some(m) = mode {
+                    attr.perm = txn.check_chmod(&attr, &cred, as_file_perm(m)).await?;
+                    txn.sync_acl(&attr).await?;
+                }
+                txn.chown(&mut attr, uid, gid).await?;
+                if let Some(size) = size {
+                    txn.truncate(&mut attr, size).await?;
+                }
+                // timestamps are omitted if `UTIME_OMIT` is passed to utimensat
+                let now = SystemTime::now();
+                let time = |time| match time {
+                    TimeOrNow::SpecificTime(t) => t,
+                    TimeOrNow::Now => now,
                 };
-                attr.ctime = ctime.unwrap_or(SystemTime::now());
+                attr.atime = atime.map(time).unwrap_or(attr.atime);
+                attr.mtime = mtime.map(time).unwrap_or(attr.mtime);
+                attr.ctime = ctime.unwrap_or(now);
                 attr.crtime = crtime.unwrap_or(attr.crtime);
                 attr.flags = flags.unwrap_or(attr.flags);
                 txn.save_inode(&attr).await?;
@@ -304,12 +968,24 @@ impl AsyncFileSystem for TiFs {
     }
 
     #[tracing::instrument]
-    async fn readdir(&self, ino: u64, _fh: u64, mut offset: i64) -> Result<Dir> {
+    async fn readdir(
+        &self,
+        _cred: &Credentials,
+        ino: u64,
+        _fh: u64,
+        mut offset: i64,
+    ) -> Result<Dir> {
         let mut dir = Dir::offset(offset as usize);
 
         if offset == 0 {
+            let parent = if ino == SNAPSHOTS_INODE {
+                ROOT_INODE
+            } else {
+                self.spin_read_only(move |_, txn| Box::pin(txn.read_parent(ino)))
+                    .await?
+            };
             dir.push(DirItem {
-                ino: ROOT_INODE,
+                ino: parent,
                 name: "..".to_string(),
                 typ: FileType::Directory,
             });
@@ -325,7 +1001,27 @@ impl AsyncFileSystem for TiFs {
 
         offset -= 2.min(offset);
 
-        let directory = self.read_dir(ino).await?;
+        let directory: Directory = if ino == SNAPSHOTS_INODE {
+            self.read_snapshots()
+                .await?
+                .into_iter()
+                .map(|snapshot| DirItem {
+                    ino: snapshot.root,
+                    name: snapshot.name,
+                    typ: FileType::Directory,
+                })
+                .collect()
+        } else {
+            let mut directory = self.read_dir(ino).await?;
+            if ino == ROOT_INODE {
+                directory.push(DirItem {
+                    ino: SNAPSHOTS_INODE,
+                    name: Self::SNAPSHOTS_DIR.to_string(),
+                    typ: FileType::Directory,
+                });
+            }
+            directory
+        };
         for (item) in directory.into_iter().skip(offset as usize) {
             dir.push(item)
         }
@@ -334,23 +1030,28 @@ impl AsyncFileSystem for TiFs {
     }
 
     #[tracing::instrument]
-    async fn open(&self, ino: u64, flags: i32) -> Result<Open> {
-        // TODO: deal with flags
-        let fh = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.open(ino)))
+    async fn opendir(&self, cred: &Credentials, ino: u64, flags: i32) -> Result<Open> {
+        if ino != SNAPSHOTS_INODE {
+            let cred = self.resolve(cred).await;
+            self.spin_read_only(move |_, txn| {
+                let cred = cred.clone();
+                Box::pin(async move { txn.check_access(ino, &cred, Self::open_mask(flags)).await })
+            })
             .await?;
-
-        let mut open_flags = 0;
-        if self.direct_io || flags | O_DIRECT != 0 {
-            open_flags |= FOPEN_DIRECT_IO;
         }
+        Ok(Open::new(0, 0))
+    }
 
-        Ok(Open::new(fh, open_flags))
+    #[tracing::instrument]
+    async fn open(&self, cred: &Credentials, ino: u64, flags: i32) -> Result<Open> {
+        let cred = self.resolve(cred).await;
+        self.open_handler(Some(cred), ino, flags).await
     }
 
     #[tracing::instrument]
     async fn read(
         &self,
+        _cred: &Credentials,
         ino: u64,
         fh: u64,
         offset: i64,
@@ -358,15 +1059,26 @@ impl AsyncFileSystem for TiFs {
         _flags: i32,
         _lock_owner: Option<u64>,
     ) -> Result<Data> {
-        let data = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.read(ino, fh, offset, size)))
+        let handler = self.read_fh(ino, fh)?;
+        let (stale_atime, data) = self
+            .spin_read_only(move |fs, txn| {
+                Box::pin(async move {
+                    let inode = txn.read_inode(ino).await?;
+                    let data = txn.read(&inode, fh, &handler, offset, size).await?;
+                    Ok((fs.atime.need_update(&inode), data))
+                })
+            })
             .await?;
+        if stale_atime {
+            self.update_atime(ino).await?;
+        }
         Ok(Data::new(data))
     }
 
     #[tracing::instrument(skip(data))]
     async fn write(
         &self,
+        _cred: &Credentials,
         ino: u64,
         fh: u64,
         offset: i64,
@@ -375,10 +1087,22 @@ impl AsyncFileSystem for TiFs {
         _flags: i32,
         _lock_owner: Option<u64>,
     ) -> Result<Write> {
+        let handler = self.read_fh(ino, fh)?;
         let data: Bytes = data.into();
-        let len = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.write(ino, fh, offset, data.clone())))
-            .await?;
+        let len = if data.len() as u64 > Self::MAX_TXN_BLOCKS * Self::BLOCK_SIZE {
+            self.write_chunked(ino, fh, handler, offset, data).await?
+        } else {
+            self.spin_no_delay(move |fs, txn| {
+                let data = data.clone();
+                Box::pin(async move {
+                    if data.len() as u64 <= Self::DELTA_THRESHOLD {
+                        txn.lend_timestamp(Txn::timestamp(&fs.client).await?);
+                    }
+                    txn.write(ino, fh, &handler, offset, data).await
+                })
+            })
+            .await?
+        };
         Ok(Write::new(len as u32))
     }
 
@@ -386,56 +1110,160 @@ impl AsyncFileSystem for TiFs {
     #[tracing::instrument]
     async fn mkdir(
         &self,
+        cred: &Credentials,
         parent: u64,
         name: ByteString,
         mode: u32,
-        gid: u32,
-        uid: u32,
-        _umask: u32,
+        umask: u32,
     ) -> Result<Entry> {
         Self::check_file_name(&name)?;
+        Self::check_snapshots_dir(parent, &name, true)?;
+        let cred = self.resolve(cred).await;
         let attr = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.mkdir(parent, name.clone(), mode, gid, uid)))
+            .spin_no_delay(move |_, txn| {
+                let cred = cred.clone();
+                let name = name.clone();
+                Box::pin(async move {
+                    txn.check_access(parent, &cred, W_OK | X_OK).await?;
+                    txn.mkdir(parent, name, mode, &cred, umask).await
+                })
+            })
             .await?;
-        Ok(Entry::new(attr.into(), 0))
+        Ok(Entry::new(attr.file_attr, attr.generation))
     }
 
     #[tracing::instrument]
-    async fn rmdir(&self, parent: u64, raw_name: ByteString) -> Result<()> {
+    async fn rmdir(&self, cred: &Credentials, parent: u64, raw_name: ByteString) -> Result<()> {
         Self::check_file_name(&raw_name)?;
-        self.spin_no_delay(move |_, txn| Box::pin(txn.rmdir(parent, raw_name.clone())))
-            .await
+        Self::check_snapshots_dir(parent, &raw_name, false)?;
+        let cred = self.resolve(cred).await;
+        self.spin_no_delay(move |_, txn| {
+            let cred = cred.clone();
+            let name = raw_name.clone();
+            Box::pin(async move {
+                txn.check_access(parent, &cred, W_OK | X_OK).await?;
+                txn.check_sticky(parent, name.clone(), &cred).await?;
+                txn.rmdir(parent, name).await
+            })
+        })
+        .await
     }
 
     #[tracing::instrument]
     async fn mknod(
         &self,
+        cred: &Credentials,
         parent: u64,
         name: ByteString,
         mode: u32,
-        gid: u32,
-        uid: u32,
-        _umask: u32,
+        umask: u32,
         rdev: u32,
     ) -> Result<Entry> {
         Self::check_file_name(&name)?;
+        Self::check_snapshots_dir(parent, &name, true)?;
+        let cred = self.resolve(cred).await;
         let attr = self
             .spin_no_delay(move |_, txn| {
-                Box::pin(txn.make_inode(parent, name.clone(), mode, gid, uid, rdev))
+                let cred = cred.clone();
+                let name = name.clone();
+                Box::pin(async move {
+                    txn.check_access(parent, &cred, W_OK | X_OK).await?;
+                    txn.make_inode(parent, name, mode, &cred, rdev, umask).await
+                })
             })
             .await?;
-        Ok(Entry::new(attr.into(), 0))
+        Ok(Entry::new(attr.file_attr, attr.generation))
+    }
+
+    #[tracing::instrument(skip(value))]
+    async fn setxattr(
+        &self,
+        cred: &Credentials,
+        ino: u64,
+        name: ByteString,
+        value: Vec<u8>,
+        flags: i32,
+        _position: u32,
+    ) -> Result<()> {
+        let cred = self.resolve(cred).await;
+        self.spin_no_delay(move |_, txn| {
+            let cred = cred.clone();
+            let name = name.clone();
+            let value = value.clone();
+            Box::pin(async move {
+                txn.check_xattr_access(ino, &name, &cred, W_OK).await?;
+                txn.set_xattr(ino, name, value, flags).await
+            })
+        })
+        .await
     }
 
     #[tracing::instrument]
-    async fn access(&self, ino: u64, mask: i32) -> Result<()> {
-        Ok(())
+    async fn getxattr(
+        &self,
+        cred: &Credentials,
+        ino: u64,
+        name: ByteString,
+        size: u32,
+    ) -> Result<Xattr> {
+        if ino == SNAPSHOTS_INODE {
+            return Err(FsError::XattrNotFound {
+                ino,
+                name: name.to_string(),
+            });
+        }
+        let cred = self.resolve(cred).await;
+        let value = self
+            .spin_read_only(move |_, txn| {
+                let cred = cred.clone();
+                let name = name.clone();
+                Box::pin(async move {
+                    txn.check_xattr_access(ino, &name, &cred, R_OK).await?;
+                    txn.get_xattr(ino, name).await
+                })
+            })
+            .await?;
+        Self::reply_xattr(value, size)
+    }
+
+    #[tracing::instrument]
+    async fn listxattr(&self, _cred: &Credentials, ino: u64, size: u32) -> Result<Xattr> {
+        if ino == SNAPSHOTS_INODE {
+            return Self::reply_xattr(Vec::new(), size);
+        }
+        let names = self
+            .spin_read_only(move |_, txn| Box::pin(txn.list_xattr(ino)))
+            .await?;
+        Self::reply_xattr(names, size)
+    }
+
+    #[tracing::instrument]
+    async fn removexattr(&self, cred: &Credentials, ino: u64, name: ByteString) -> Result<()> {
+        let cred = self.resolve(cred).await;
+        self.spin_no_delay(move |_, txn| {
+            let cred = cred.clone();
+            let name = name.clone();
+            Box::pin(async move {
+                txn.check_xattr_access(ino, &name, &cred, W_OK).await?;
+                txn.remove_xattr(ino, name).await
+            })
+        })
+        .await
+    }
+
+    #[tracing::instrument]
+    async fn access(&self, cred: &Credentials, ino: u64, mask: i32) -> Result<()> {
+        let cred = self.resolve(cred).await;
+        self.spin_read_only(move |_, txn| {
+            let cred = cred.clone();
+            Box::pin(async move { txn.check_access(ino, &cred, mask).await })
+        })
+        .await
     }
 
     async fn create(
         &self,
-        uid: u32,
-        gid: u32,
+        cred: &Credentials,
         parent: u64,
         name: ByteString,
         mode: u32,
@@ -443,8 +1271,9 @@ impl AsyncFileSystem for TiFs {
         flags: i32,
     ) -> Result<Create> {
         Self::check_file_name(&name)?;
-        let entry = self.mknod(parent, name, mode, gid, uid, umask, 0).await?;
-        let open = self.open(entry.stat.ino, flags).await?;
+        let entry = self.mknod(cred, parent, name, mode, umask, 0).await?;
+        // the creator can always open the new file, regardless of its mode
+        let open = self.open_handler(None, entry.stat.ino, flags).await?;
         Ok(Create::new(
             entry.stat,
             entry.generation,
@@ -453,76 +1282,146 @@ impl AsyncFileSystem for TiFs {
         ))
     }
 
-    async fn lseek(&self, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<Lseek> {
-        self.spin_no_delay(move |_, txn| {
-            Box::pin(async move {
-                let mut file_handler = txn.read_fh(ino, fh).await?;
-                let inode = txn.read_inode(ino).await?;
-                let target_cursor = match whence {
-                    SEEK_SET => offset,
-                    SEEK_CUR => file_handler.cursor as i64 + offset,
-                    SEEK_END => inode.size as i64 + offset,
-                    _ => return Err(FsError::UnknownWhence { whence }),
-                };
-
-                if target_cursor < 0 {
-                    return Err(FsError::InvalidOffset {
-                        ino: inode.ino,
-                        offset: target_cursor,
-                    });
+    async fn lseek(
+        &self,
+        _cred: &Credentials,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+    ) -> Result<Lseek> {
+        let handler = self.read_fh(ino, fh)?;
+        let target_cursor = match whence {
+            SEEK_SET => offset,
+            SEEK_CUR => handler.cursor as i64 + offset,
+            SEEK_END => {
+                let inode = self
+                    .spin_read_only(move |_, txn| Box::pin(txn.read_inode(ino)))
+                    .await?;
+                inode.size as i64 + offset
+            }
+            SEEK_DATA | SEEK_HOLE => {
+                if offset < 0 {
+                    return Err(FsError::NoSuchOffset { ino, offset });
                 }
+                let hole = whence == SEEK_HOLE;
+                self.spin_read_only(move |_, txn| {
+                    Box::pin(async move {
+                        let inode = txn.read_inode(ino).await?;
+                        txn.seek_data(&inode, offset as u64, hole).await
+                    })
+                })
+                .await? as i64
+            }
+            _ => return Err(FsError::UnknownWhence { whence }),
+        };
 
-                file_handler.cursor = target_cursor as u64;
-                txn.save_fh(ino, fh, &file_handler).await?;
-                Ok(Lseek::new(target_cursor))
-            })
-        })
-        .await
+        if target_cursor < 0 {
+            return Err(FsError::InvalidOffset {
+                ino,
+                offset: target_cursor,
+            });
+        }
+
+        if let Some(handler) = self.handlers.lock().unwrap().get_mut(&fh) {
+            handler.cursor = target_cursor as u64;
+        }
+        Ok(Lseek::new(target_cursor))
     }
 
     async fn release(
         &self,
+        _cred: &Credentials,
         ino: u64,
         fh: u64,
         _flags: i32,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
         _flush: bool,
     ) -> Result<()> {
-        self.spin_no_delay(move |_, txn| Box::pin(txn.close(ino, fh)))
-            .await
+        let handler = self.read_fh(ino, fh)?;
+        // flock locks are released with the last handler
+        if let Some(owner) = lock_owner {
+            let session = self.session();
+            self.spin_no_delay(move |_, txn| Box::pin(txn.release_locks(ino, session, owner)))
+                .await?;
+            self.notify_locks(ino);
+        }
+        self.handlers.lock().unwrap().remove(&fh);
+        self.remove_opened(&handler).await
+    }
+
+    /// POSIX locks of the owner are released when any of its files is closed.
+    async fn flush(&self, _cred: &Credentials, ino: u64, _fh: u64, lock_owner: u64) -> Result<()> {
+        let session = self.session();
+        self.spin_no_delay(move |_, txn| Box::pin(txn.release_locks(ino, session, lock_owner)))
+            .await?;
+        self.notify_locks(ino);
+        Ok(())
     }
 
     /// Create a hard link.
-    async fn link(&self, ino: u64, newparent: u64, newname: ByteString) -> Result<Entry> {
+    async fn link(
+        &self,
+        cred: &Credentials,
+        ino: u64,
+        newparent: u64,
+        newname: ByteString,
+    ) -> Result<Entry> {
         Self::check_file_name(&newname)?;
+        Self::check_snapshots_dir(newparent, &newname, true)?;
+        let cred = self.resolve(cred).await;
         let inode = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.link(ino, newparent, newname.clone())))
+            .spin_no_delay(move |_, txn| {
+                let cred = cred.clone();
+                let newname = newname.clone();
+                Box::pin(async move {
+                    txn.check_access(newparent, &cred, W_OK | X_OK).await?;
+                    txn.link(ino, newparent, newname).await
+                })
+            })
             .await?;
-        Ok(Entry::new(inode.into(), 0))
+        Ok(Entry::new(inode.file_attr, inode.generation))
     }
 
-    async fn unlink(&self, parent: u64, raw_name: ByteString) -> Result<()> {
-        self.spin_no_delay(move |_, txn| Box::pin(txn.unlink(parent, raw_name.clone())))
-            .await
+    async fn unlink(&self, cred: &Credentials, parent: u64, raw_name: ByteString) -> Result<()> {
+        Self::check_snapshots_dir(parent, &raw_name, false)?;
+        let cred = self.resolve(cred).await;
+        self.spin_no_delay(move |_, txn| {
+            let cred = cred.clone();
+            let name = raw_name.clone();
+            Box::pin(async move {
+                txn.check_access(parent, &cred, W_OK | X_OK).await?;
+                txn.check_sticky(parent, name.clone(), &cred).await?;
+                txn.unlink(parent, name).await
+            })
+        })
+        .await
     }
 
     async fn rename(
         &self,
+        cred: &Credentials,
         parent: u64,
         raw_name: ByteString,
         newparent: u64,
         new_raw_name: ByteString,
-        _flags: u32,
+        flags: u32,
     ) -> Result<()> {
         Self::check_file_name(&raw_name)?;
         Self::check_file_name(&new_raw_name)?;
+        Self::check_snapshots_dir(parent, &raw_name, false)?;
+        Self::check_snapshots_dir(newparent, &new_raw_name, true)?;
+        let cred = self.resolve(cred).await;
         self.spin_no_delay(move |_, txn| {
+            let cred = cred.clone();
             let name = raw_name.clone();
             let new_name = new_raw_name.clone();
             Box::pin(async move {
-                let ino = txn.lookup(parent, name.clone()).await?;
-                txn.link(ino, newparent, new_name).await?;
-                txn.unlink(parent, name).await
+                txn.check_access(parent, &cred, W_OK | X_OK).await?;
+                txn.check_access(newparent, &cred, W_OK | X_OK).await?;
+                txn.check_sticky(parent, name.clone(), &cred).await?;
+                txn.check_sticky(newparent, new_name.clone(), &cred).await?;
+                txn.rename(parent, name, newparent, new_name, flags).await
             })
         })
         .await
@@ -531,45 +1430,58 @@ impl AsyncFileSystem for TiFs {
     #[tracing::instrument]
     async fn symlink(
         &self,
-        gid: u32,
-        uid: u32,
+        cred: &Credentials,
         parent: u64,
         name: ByteString,
         link: ByteString,
     ) -> Result<Entry> {
         Self::check_file_name(&name)?;
+        Self::check_snapshots_dir(parent, &name, true)?;
+        let cred = self.resolve(cred).await;
         self.spin_no_delay(move |_, txn| {
+            let cred = cred.clone();
             let name = name.clone();
             let link = link.clone();
             Box::pin(async move {
+                txn.check_access(parent, &cred, W_OK | X_OK).await?;
                 let mut attr = txn
                     .make_inode(
                         parent,
                         name,
                         make_mode(FileType::Symlink, 0o777),
-                        gid,
-                        uid,
+                        &cred,
+                        0,
                         0,
                     )
                     .await?;
 
                 txn.write_link(&mut attr, link.into_bytes()).await?;
-                Ok(Entry::new(attr.into(), 0))
+                Ok(Entry::new(attr.file_attr, attr.generation))
             })
         })
         .await
     }
 
-    async fn readlink(&self, ino: u64) -> Result<Data> {
-        self.spin(None, move |_, txn| {
-            Box::pin(async move { Ok(Data::new(txn.read_link(ino).await?)) })
-        })
-        .await
+    async fn readlink(&self, _cred: &Credentials, ino: u64) -> Result<Data> {
+        let (stale_atime, data) = self
+            .spin_read_only(move |fs, txn| {
+                Box::pin(async move {
+                    let inode = txn.read_inode(ino).await?;
+                    let data = txn.read_link(&inode).await?;
+                    Ok((fs.atime.need_update(&inode), data))
+                })
+            })
+            .await?;
+        if stale_atime {
+            self.update_atime(ino).await?;
+        }
+        Ok(Data::new(data))
     }
 
     #[tracing::instrument]
     async fn fallocate(
         &self,
+        _cred: &Credentials,
         ino: u64,
         fh: u64,
         offset: i64,
@@ -585,47 +1497,41 @@ impl AsyncFileSystem for TiFs {
         .await?;
         Ok(())
     }
-    // TODO: Find an api to calculate total and available space on tikv.
-    async fn statfs(&self, _ino: u64) -> Result<StatFs> {
+    async fn statfs(&self, _cred: &Credentials, _ino: u64) -> Result<StatFs> {
         let bsize = Self::BLOCK_SIZE as u32;
         let namelen = Self::MAX_NAME_LEN;
-        let (ffree, blocks, files) = self
-            .spin_no_delay(move |_, txn| {
+        let (meta, usage) = self
+            .spin_read_only(move |_, txn| {
                 Box::pin(async move {
-                    let next_inode = txn
-                        .read_meta()
-                        .await?
-                        .map(|meta| meta.inode_next)
-                        .unwrap_or(ROOT_INODE);
-                    let (b, f) = txn
-                        .scan(
-                            ScopedKey::inode_range(ROOT_INODE..next_inode),
-                            (next_inode - ROOT_INODE) as u32,
-                        )
-                        .await?
-                        .map(|pair| Inode::deserialize(pair.value()))
-                        .try_fold((0, 0), |(blocks, files), inode| {
-                            Ok::<_, FsError>((blocks + inode?.blocks, files + 1))
-                        })?;
-                    Ok((std::u64::MAX - next_inode, b, f))
+                    let meta = txn.read_meta().await?.unwrap_or_default();
+                    Ok((meta, txn.read_usage().await?))
                 })
             })
             .await?;
+        let blocks = meta
+            .capacity
+            .map(|capacity| capacity / Self::BLOCK_SIZE)
+            .unwrap_or(std::u64::MAX);
+        let bfree = blocks.saturating_sub(usage.blocks.max(0) as u64);
         Ok(StatFs::new(
             blocks,
-            std::u64::MAX,
-            std::u64::MAX,
-            files,
-            ffree,
+            bfree,
+            bfree,
+            usage.files.max(0) as u64,
+            std::u64::MAX - meta.inode_next,
             bsize,
             namelen,
             0,
         ))
     }
 
+    /// A blocking request waits until the conflicting lock is released by this mount, or
+    /// retries after a delay growing to `TiFs::LOCK_WAIT_MAX`. It is cancelled if the
+    /// waiting process has a signal to handle.
     #[tracing::instrument]
     async fn setlk(
         &self,
+        cred: &Credentials,
         ino: u64,
         fh: u64,
         lock_owner: u64,
@@ -635,103 +1541,70 @@ impl AsyncFileSystem for TiFs {
         pid: u32,
         sleep: bool,
     ) -> Result<()> {
-        let not_again = self.spin_no_delay(move |_, txn| {
-            Box::pin(async move {
-                let mut inode = txn.read_inode(ino).await?;
-                warn!("setlk, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                if inode.file_attr.kind == FileType::Directory {
-                    return Err(FsError::InvalidLock);
-                }
-                match typ {
-                    F_RDLCK => {
-                        if inode.lock_state.lk_type == F_WRLCK {
-                            if sleep {
-                                warn!("setlk F_RDLCK return sleep, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                                return Ok(false)
-                            }
-                            return Err(FsError::InvalidLock);
-                        }
-                        inode.lock_state.owner_set.insert(lock_owner);
-                        inode.lock_state.lk_type = F_RDLCK;
-                        txn.save_inode(&inode).await?;
-                        warn!("setlk F_RDLCK return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                        Ok(true)
+        // like linux, a read lock needs a readable handler and a write lock a writable one
+        let handler = self.read_fh(ino, fh)?;
+        let access = match typ {
+            F_RDLCK if !handler.readable() => Some("reading"),
+            F_WRLCK if !handler.writable() => Some("writing"),
+            _ => None,
+        };
+        if let Some(access) = access {
+            return Err(FsError::BadAccessMode { ino, fh, access });
+        }
+        let lock = LockRange::new(self.session(), lock_owner, pid, typ, start, end);
+        let mut delay = Self::BUSY_DELAY;
+        loop {
+            // watch before trying, so that a release right after the conflict is not missed
+            let released = self.watch_locks(ino);
+            self.check_lease()?;
+            let now = Txn::clock(&self.client).await?;
+            match self
+                .spin_no_delay(move |_, txn| Box::pin(txn.set_lock(ino, lock, now)))
+                .await
+            {
+                Err(FsError::LockConflict { ino: _, owner }) if sleep => {
+                    let pid = cred.pid;
+                    if spawn_blocking(move || Credentials::interrupted(pid)).await {
+                        return Err(FsError::Interrupted { pid: cred.pid });
                     }
-                    F_WRLCK => match inode.lock_state.lk_type {
-                        F_RDLCK => {
-                            if inode.lock_state.owner_set.len() == 1
-                                && inode.lock_state.owner_set.get(&lock_owner) == Some(&lock_owner)
-                            {
-                                inode.lock_state.lk_type = F_WRLCK;
-                                txn.save_inode(&inode).await?;
-                                warn!("setlk F_WRLCK on F_RDLCK return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                                return Ok(true);
-                            }
-                            if sleep {
-                                warn!("setlk F_WRLCK on F_RDLCK sleep return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                                return Ok(false)
-                            }
-                            return Err(FsError::InvalidLock);
-                        },
-                        F_UNLCK => {
-                            inode.lock_state.owner_set.clear();
-                            inode.lock_state.owner_set.insert(lock_owner);
-                            inode.lock_state.lk_type = F_WRLCK;
-                            warn!("setlk F_WRLCK on F_UNLCK return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                            txn.save_inode(&inode).await?;
-                            Ok(true)
-                        },
-                        F_WRLCK => {
-                            if sleep {
-                                warn!("setlk F_WRLCK on F_WRLCK return sleep, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                                return Ok(false)
-                            }
-                            return Err(FsError::InvalidLock);
-                        },
-                        _ => return Err(FsError::InvalidLock)
-                    },
-                    F_UNLCK => {
-                        inode.lock_state.owner_set.remove(&lock_owner);
-                        if inode.lock_state.owner_set.is_empty() {
-                            inode.lock_state.lk_type = F_UNLCK;
-                        }
-                        txn.save_inode(&inode).await?;
-                        warn!("setlk F_UNLCK return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                        Ok(true)
+                    trace!("wait for the lock of owner({}) on inode({})", owner, ino);
+                    let _ = timeout(delay, released).await;
+                    delay = (delay * 2).min(Self::LOCK_WAIT_MAX);
+                }
+                Ok(()) => {
+                    if typ != F_WRLCK {
+                        self.notify_locks(ino);
                     }
-                    _ => return Err(FsError::InvalidLock)
+                    break Ok(());
                 }
-            })
-        })
-        .await?;
-        if !not_again {
-            if self.setlkw(ino, lock_owner, typ).await? {
-                return Ok(());
+                result => break result,
             }
-            return Err(FsError::InvalidLock);
         }
-        return Ok(());
     }
 
     #[tracing::instrument]
     async fn getlk(
         &self,
+        _cred: &Credentials,
         ino: u64,
-        fh: u64,
+        _fh: u64,
         lock_owner: u64,
         start: u64,
         end: u64,
         typ: i32,
         pid: u32,
     ) -> Result<Lock> {
-        // TODO: read only operation need not txn?
-        self.spin_no_delay(move |_, txn| {
-            Box::pin(async move {
-                let inode = txn.read_inode(ino).await?;
-                warn!("getlk, inode:{:?}, pid:{:?}", inode, pid);
-                Ok(Lock::_new(0, 0, inode.lock_state.lk_type, 0))
-            })
-        })
-        .await
+        let lock = LockRange::new(self.session(), lock_owner, pid, typ, start, end);
+        self.check_lease()?;
+        let now = Txn::clock(&self.reader).await?;
+        let conflict = self
+            .spin_read_only(move |_, txn| Box::pin(txn.get_lock(ino, lock, now)))
+            .await?;
+        Ok(Lock::_new(
+            conflict.start,
+            conflict.end,
+            conflict.typ,
+            conflict.pid,
+        ))
     }
 }
@@ -1,78 +1,261 @@
-use std::ops::{Deref, DerefMut};
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Range;
+use std::sync::Mutex;
 use std::time::SystemTime;
 
 use bytes::Bytes;
 use bytestring::ByteString;
-use fuser::{FileAttr, FileType};
-use tikv_client::{Transaction, TransactionClient};
-use tracing::{debug, trace};
-
-use super::block::empty_block;
+use fuser::{FileAttr, FileType, TimeOrNow};
+use libc::{
+    F_OK, F_UNLCK, RENAME_EXCHANGE, RENAME_NOREPLACE, S_ISGID, S_ISUID, S_ISVTX, S_IXGRP, W_OK,
+    XATTR_CREATE, XATTR_REPLACE,
+};
+use tikv_client::{
+    BoundRange, Key, KvPair, Snapshot as KvSnapshot, TimestampExt, Transaction, TransactionClient,
+    TransactionOptions, Value,
+};
+use tracing::{debug, trace, warn};
+
+use super::acl::{Acl, ACL_ACCESS, ACL_DEFAULT};
+use super::block::{empty_block, BlockDelta, BlockLink, BlockShare};
+use super::credentials::Credentials;
 use super::dir::Directory;
 use super::error::{FsError, Result};
 use super::file_handler::FileHandler;
 use super::index::Index;
-use super::inode::Inode;
-use super::key::{ScopedKey, ROOT_INODE};
+use super::inode::{AtimePolicy, Inode, InodeAtime, InodeTimes};
+use super::intent::{Intent, IntentKind};
+use super::key::{ScopedKey, ROOT_INODE, SNAPSHOTS_INODE};
+use super::lock::{LockRange, LockTable};
 use super::meta::Meta;
 use super::mode::{as_file_kind, as_file_perm, make_mode};
+use super::quota::{Quota, QuotaKind, QuotaUsage};
 use super::reply::DirItem;
+use super::session::Session;
+use super::snapshot::{Snapshot, TreeFork, TreeTask};
 use super::tikv_fs::TiFs;
+use super::usage::{Space, Usage};
+
+/// Name of the index from a directory to its parent.
+const PARENT_NAME: &str = "..";
+
+/// The transaction of a `Txn`, which is a snapshot if it's read-only.
+enum Inner {
+    Optimistic(Transaction),
+    ReadOnly(KvSnapshot),
+}
 
-pub struct Txn(Transaction);
+pub struct Txn {
+    txn: Inner,
+    intents: Vec<u64>,
+    inodes: Range<u64>,
+    leased: bool,
+    compactions: Vec<(u64, u64)>,
+    clock: u64,
+    space: Option<Space>,
+    allocated: i64,
+    timestamp: Option<u64>,
+    /// Inodes as stored in this transaction, so that saving an inode read before writes only
+    /// the keys whose values are changed without reading them again.
+    stored: Mutex<HashMap<u64, Inode>>,
+}
 
 impl Txn {
+    fn new(txn: Inner, clock: u64) -> Self {
+        Txn {
+            txn,
+            intents: Vec::new(),
+            inodes: 0..0,
+            leased: false,
+            compactions: Vec::new(),
+            clock,
+            space: None,
+            allocated: 0,
+            timestamp: None,
+            stored: Mutex::new(HashMap::new()),
+        }
+    }
+
     pub async fn begin_optimistic(client: &TransactionClient) -> Result<Self> {
-        Ok(Txn(client.begin_optimistic().await?))
+        let clock = Self::clock(client).await?;
+        Ok(Self::new(
+            Inner::Optimistic(client.begin_optimistic().await?),
+            clock,
+        ))
     }
 
-    pub async fn open(&mut self, ino: u64) -> Result<u64> {
-        let mut inode = self.read_inode(ino).await?;
-        let fh = inode.next_fh;
-        self.save_fh(ino, fh, &FileHandler::default()).await?;
-        inode.next_fh += 1;
-        inode.opened_fh += 1;
-        self.save_inode(&inode).await?;
-        Ok(fh)
+    /// Begin a transaction which only reads a snapshot at the current timestamp.
+    ///
+    /// It never conflicts, and has nothing to commit or roll back.
+    pub async fn begin_snapshot(client: &TransactionClient) -> Result<Self> {
+        let timestamp = client.current_timestamp().await?;
+        let clock = timestamp.physical as u64;
+        let options = TransactionOptions::new_optimistic().read_only();
+        Ok(Self::new(
+            Inner::ReadOnly(client.snapshot(timestamp, options)),
+            clock,
+        ))
     }
 
-    pub async fn close(&mut self, ino: u64, fh: u64) -> Result<()> {
-        self.read_fh(ino, fh).await?;
-        self.delete(ScopedKey::handler(ino, fh)).await?;
+    fn read_only(&self) -> bool {
+        matches!(self.txn, Inner::ReadOnly(_))
+    }
 
-        let mut inode = self.read_inode(ino).await?;
-        inode.opened_fh -= 1;
-        self.save_inode(&inode).await
+    /// The physical time of TiKV in milliseconds, which all mounts agree on unlike their
+    /// local clocks.
+    pub async fn clock(client: &TransactionClient) -> Result<u64> {
+        Ok(client.current_timestamp().await?.physical as u64)
+    }
+
+    /// A timestamp of TiKV taken after a transaction begins, which is greater than the
+    /// commit timestamps of all transactions committed before it begins.
+    pub async fn timestamp(client: &TransactionClient) -> Result<u64> {
+        Ok(client.current_timestamp().await?.version())
+    }
+
+    /// Take inodes whose intents were set in this transaction, to be completed after committing.
+    pub fn take_intents(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.intents)
+    }
+
+    /// Take blocks whose deltas piled up in this transaction, to be compacted after committing.
+    pub fn take_compactions(&mut self) -> Vec<(u64, u64)> {
+        std::mem::take(&mut self.compactions)
+    }
+
+    /// Lend the space of the volume cached by the mount, to check the capacity without
+    /// reading all shards of the usage counters.
+    pub fn lend_space(&mut self, space: Space) {
+        self.space = Some(space);
+    }
+
+    /// Take the blocks allocated in this transaction, to be counted in the cached space
+    /// after committing.
+    pub fn take_allocated(&mut self) -> i64 {
+        std::mem::take(&mut self.allocated)
+    }
+
+    /// Lend a timestamp taken by `Txn::timestamp` after this transaction began, to be the
+    /// sequence number of deltas written in it.
+    pub fn lend_timestamp(&mut self, timestamp: u64) {
+        self.timestamp = Some(timestamp);
+    }
+
+    /// Lend a leased range of inode numbers to this transaction.
+    pub fn lend_inodes(&mut self, inodes: Range<u64>) {
+        self.inodes = inodes;
+    }
+
+    /// Take back the unused inode numbers.
+    ///
+    /// A range leased in this transaction is valid only if it is committed,
+    /// so it is dropped if `committed` is false.
+    pub fn take_inodes(&mut self, committed: bool) -> Option<Range<u64>> {
+        let inodes = std::mem::replace(&mut self.inodes, 0..0);
+        if inodes.is_empty() || (self.leased && !committed) {
+            None
+        } else {
+            Some(inodes)
+        }
     }
 
-    pub async fn read_fh(&self, ino: u64, fh: u64) -> Result<FileHandler> {
-        let data = self
-            .get(ScopedKey::handler(ino, fh))
+    /// Write the inode key back as it is, so that the transaction conflicts with any other
+    /// one writing the inode, even if nothing of the inode is changed.
+    async fn touch_inode(&mut self, ino: u64) -> Result<()> {
+        let stat = self
+            .get(ScopedKey::inode(ino))
             .await?
-            .ok_or_else(|| FsError::FhNotFound { ino, fh })?;
-        FileHandler::deserialize(&data)
+            .ok_or(FsError::InodeNotFound { inode: ino })?;
+        self.put(ScopedKey::inode(ino), stat).await
     }
 
-    pub async fn save_fh(&mut self, ino: u64, fh: u64, handler: &FileHandler) -> Result<()> {
+    /// Record that a session opens an inode, so that the inode is not reclaimed before
+    /// it is closed, even if it is unlinked.
+    ///
+    /// The inode key is touched, so that an open conflicts with a concurrent unlink or close
+    /// which finds the inode not opened and reclaims it.
+    pub async fn open(&mut self, ino: u64, session: u64) -> Result<()> {
+        self.touch_inode(ino).await?;
+        self.put(ScopedKey::opened(ino, session), Vec::new())
+            .await?;
+        self.put(ScopedKey::session_opened(session, ino), Vec::new())
+            .await?;
+        Ok(())
+    }
+
+    /// Remove the record of an opened inode, reclaim the inode if it is unlinked and
+    /// not opened by any session.
+    ///
+    /// The inode key is touched even if the inode is still linked, so that a close conflicts
+    /// with a concurrent unlink which finds the inode opened and leaves it.
+    pub async fn close(&mut self, ino: u64, session: u64) -> Result<()> {
+        self.delete(ScopedKey::opened(ino, session)).await?;
+        self.delete(ScopedKey::session_opened(session, ino)).await?;
+        match self.touch_inode(ino).await {
+            Err(FsError::InodeNotFound { inode: _ }) => return Ok(()),
+            result => result?,
+        }
+        let inode = self.read_inode(ino).await?;
+        if inode.nlink == 0 {
+            self.save_inode(&inode).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether any session opens the inode.
+    pub async fn is_opened(&self, ino: u64) -> Result<bool> {
         Ok(self
-            .put(ScopedKey::handler(ino, fh), handler.serialize()?)
-            .await?)
+            .scan_keys(ScopedKey::opened_range(ino), 1)
+            .await?
+            .next()
+            .is_some())
     }
 
-    pub async fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>> {
-        let handler = self.read_fh(ino, fh).await?;
+    pub async fn read(
+        &self,
+        inode: &Inode,
+        fh: u64,
+        handler: &FileHandler,
+        offset: i64,
+        size: u32,
+    ) -> Result<Vec<u8>> {
+        if !handler.readable() {
+            return Err(FsError::BadAccessMode {
+                ino: inode.ino,
+                fh,
+                access: "reading",
+            });
+        }
         let start = handler.cursor as i64 + offset;
         if start < 0 {
             return Err(FsError::InvalidOffset {
-                ino: ino,
+                ino: inode.ino,
                 offset: start,
             });
         }
-        self.read_data(ino, start as u64, Some(size as u64)).await
+        self.read_data(inode, start as u64, Some(size as u64)).await
     }
 
-    pub async fn write(&mut self, ino: u64, fh: u64, offset: i64, data: Bytes) -> Result<usize> {
-        let handler = self.read_fh(ino, fh).await?;
+    /// Get the start offset of a write, which is the end of file if the handler is in append mode.
+    async fn write_start(
+        &self,
+        ino: u64,
+        fh: u64,
+        handler: &FileHandler,
+        offset: i64,
+    ) -> Result<u64> {
+        if !handler.writable() {
+            return Err(FsError::BadAccessMode {
+                ino,
+                fh,
+                access: "writing",
+            });
+        }
+        if handler.append() {
+            // the size is read in this transaction, so concurrent appends conflict and retry
+            return Ok(self.read_inode(ino).await?.size);
+        }
+
         let start = handler.cursor as i64 + offset;
         if start < 0 {
             return Err(FsError::InvalidOffset {
@@ -80,8 +263,19 @@ impl Txn {
                 offset: start,
             });
         }
+        Ok(start as u64)
+    }
 
-        self.write_data(ino, start as u64, data).await
+    pub async fn write(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        handler: &FileHandler,
+        offset: i64,
+        data: Bytes,
+    ) -> Result<usize> {
+        let start = self.write_start(ino, fh, handler, offset).await?;
+        self.write_data(ino, start, data).await
     }
 
     pub async fn make_inode(
@@ -89,51 +283,81 @@ impl Txn {
         parent: u64,
         name: ByteString,
         mode: u32,
-        gid: u32,
-        uid: u32,
+        cred: &Credentials,
         rdev: u32,
+        umask: u32,
     ) -> Result<Inode> {
-        let mut meta = self.read_meta().await?.unwrap_or_default();
-        let ino = meta.inode_next;
-        meta.inode_next += 1;
-
-        debug!("get ino({})", ino);
-        self.save_meta(&meta).await?;
+        let ino = self.alloc_ino().await?;
 
         let file_type = as_file_kind(mode);
+        let mut perm = as_file_perm(mode);
+        let mut gid = cred.gid;
+        let mut project = 0;
         if parent >= ROOT_INODE {
             if self.get_index(parent, name.clone()).await?.is_some() {
                 return Err(FsError::FileExist {
                     file: name.to_string(),
                 });
             }
-            self.set_index(parent, name.clone(), ino).await?;
-
-            let mut dir = self.read_dir(parent).await?;
-            debug!("read dir({:?})", &dir);
-
-            dir.push(DirItem {
-                ino,
-                name: name.to_string(),
-                typ: file_type,
-            });
+            self.add_entry(parent, name.clone(), ino, file_type).await?;
+
+            let parent_inode = self.read_inode(parent).await?;
+            project = parent_inode.project;
+            // entries of a setgid directory belong to its group, and subdirectories inherit the bit
+            if parent_inode.perm & S_ISGID as u16 != 0 {
+                gid = parent_inode.gid;
+                if file_type == FileType::Directory {
+                    perm |= S_ISGID as u16;
+                }
+            }
+        }
+        if file_type != FileType::Directory
+            && perm & S_ISGID as u16 != 0
+            && !cred.is_root()
+            && !cred.in_group(gid)
+        {
+            perm &= !S_ISGID as u16;
+        }
 
-            self.save_dir(parent, &dir).await?;
-            // TODO: update attributes of directory
+        let default_acl = if parent >= ROOT_INODE && file_type != FileType::Symlink {
+            self.read_acl(parent, ACL_DEFAULT).await?
+        } else {
+            None
+        };
+        match &default_acl {
+            // the umask is ignored if the parent has a default acl
+            Some(default) => {
+                let acl = default.inherit(perm & 0o777);
+                perm = (perm & !0o777) | acl.mode();
+                if !acl.is_minimal() {
+                    self.put(ScopedKey::xattr(ino, ACL_ACCESS), acl.encode())
+                        .await?;
+                }
+                if file_type == FileType::Directory {
+                    self.put(ScopedKey::xattr(ino, ACL_DEFAULT), default.encode())
+                        .await?;
+                }
+            }
+            None => perm &= !(umask as u16),
         }
 
-        let inode = FileAttr {
+        let now = SystemTime::now();
+        let mut inode: Inode = FileAttr {
             ino,
             size: 0,
             blocks: 0,
-            atime: SystemTime::now(),
-            mtime: SystemTime::now(),
-            ctime: SystemTime::now(),
-            crtime: SystemTime::now(),
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
             kind: file_type,
-            perm: as_file_perm(mode),
-            nlink: 1,
-            uid,
+            perm,
+            nlink: if file_type == FileType::Directory {
+                2
+            } else {
+                1
+            },
+            uid: cred.uid,
             gid,
             rdev,
             blksize: TiFs::BLOCK_SIZE as u32,
@@ -141,9 +365,12 @@ impl Txn {
             flags: 0,
         }
         .into();
+        inode.init_generation();
+        inode.project = project;
 
         debug!("made inode ({:?})", &inode);
 
+        self.charge(&inode, 0, 0, 1).await?;
         self.save_inode(&inode).await?;
         Ok(inode.into())
     }
@@ -171,31 +398,348 @@ impl Txn {
         Ok(self.delete(key).await?)
     }
 
-    pub async fn read_inode(&self, ino: u64) -> Result<Inode> {
-        let value = self
-            .get(ScopedKey::inode(ino))
+    /// Parent of a directory, indexed by the name `..`.
+    /// Directories made before the index was introduced are regarded as children of the root.
+    pub async fn read_parent(&self, ino: u64) -> Result<u64> {
+        if ino == ROOT_INODE {
+            return Ok(ROOT_INODE);
+        }
+        Ok(self
+            .get_index(ino, PARENT_NAME.into())
+            .await?
+            .unwrap_or(ROOT_INODE))
+    }
+
+    /// Add an entry into a directory, a subdirectory links back to its parent by `..`.
+    async fn add_entry(
+        &mut self,
+        parent: u64,
+        name: ByteString,
+        ino: u64,
+        typ: FileType,
+    ) -> Result<()> {
+        self.set_index(parent, name.clone(), ino).await?;
+
+        let mut dir = self.read_dir(parent).await?;
+        debug!("read dir({:?})", &dir);
+        dir.push(DirItem {
+            ino,
+            name: name.to_string(),
+            typ,
+        });
+
+        let mut parent_inode = self.save_dir(parent, &dir).await?;
+        if typ == FileType::Directory {
+            parent_inode.nlink += 1;
+            self.save_inode(&parent_inode).await?;
+            self.set_index(ino, PARENT_NAME.into(), parent).await?;
+        }
+        Ok(())
+    }
+
+    /// Point an existing entry of a directory to another inode.
+    async fn replace_entry(
+        &mut self,
+        parent: u64,
+        name: ByteString,
+        ino: u64,
+        typ: FileType,
+    ) -> Result<()> {
+        self.set_index(parent, name.clone(), ino).await?;
+
+        let mut old_typ = None;
+        let mut dir = self.read_dir(parent).await?;
+        for item in dir.iter_mut().filter(|item| item.name == &*name) {
+            old_typ = Some(item.typ);
+            item.ino = ino;
+            item.typ = typ;
+        }
+
+        let mut parent_inode = self.save_dir(parent, &dir).await?;
+        let is_dir = typ == FileType::Directory;
+        if (old_typ == Some(FileType::Directory)) != is_dir {
+            parent_inode.nlink = if is_dir {
+                parent_inode.nlink + 1
+            } else {
+                (parent_inode.nlink - 1).max(2)
+            };
+            self.save_inode(&parent_inode).await?;
+        }
+        if is_dir {
+            self.set_index(ino, PARENT_NAME.into(), parent).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove an entry from a directory, return the inode number of the entry.
+    async fn remove_entry(&mut self, parent: u64, name: ByteString) -> Result<u64> {
+        let ino = self.lookup(parent, name.clone()).await?;
+        self.remove_index(parent, name.clone()).await?;
+
+        let mut typ = None;
+        let dir: Directory = self
+            .read_dir(parent)
             .await?
-            .ok_or_else(|| FsError::InodeNotFound { inode: ino })?;
-        Ok(Inode::deserialize(&value)?)
+            .into_iter()
+            .filter(|item| {
+                if item.name == &*name {
+                    typ = Some(item.typ);
+                    false
+                } else {
+                    true
+                }
+            })
+            .collect();
+
+        let mut parent_inode = self.save_dir(parent, &dir).await?;
+        if typ == Some(FileType::Directory) {
+            // directories made before link counting may have a count of 1
+            parent_inode.nlink = (parent_inode.nlink - 1).max(2);
+            self.save_inode(&parent_inode).await?;
+            self.remove_index(ino, PARENT_NAME.into()).await?;
+        }
+        Ok(ino)
+    }
+
+    pub async fn read_inode(&self, ino: u64) -> Result<Inode> {
+        let inode = self.read_inode_unchecked(ino).await?;
+        let busy = inode
+            .intent
+            .as_ref()
+            .map_or(false, |intent| intent.busy(self.clock));
+        match &inode.intent {
+            // a busy write only stages blocks, so readers still see the last committed state
+            Some(_) if !self.intents.contains(&ino) && !(busy && self.read_only()) => {
+                if busy {
+                    Err(FsError::InodeBusy { ino })
+                } else {
+                    Err(FsError::PendingIntent { ino })
+                }
+            }
+            _ => Ok(inode),
+        }
+    }
+
+    /// Read an inode regardless of its pending intent, gathering the values of its keys.
+    pub async fn read_inode_unchecked(&self, ino: u64) -> Result<Inode> {
+        let keys = vec![
+            ScopedKey::inode(ino),
+            ScopedKey::times(ino),
+            ScopedKey::atime(ino),
+            ScopedKey::inline(ino),
+        ];
+        let (mut stat, mut times, mut atime, mut inline_data) = (None, None, None, None);
+        for pair in self.batch_get(keys).await? {
+            match ScopedKey::parse(pair.key().into())? {
+                ScopedKey::Inode(_) => stat = Some(Inode::deserialize(pair.value())?),
+                ScopedKey::Times(_) => times = Some(InodeTimes::deserialize(pair.value())?),
+                ScopedKey::Atime(_) => atime = Some(InodeAtime::deserialize(pair.value())?),
+                ScopedKey::Inline(_) => inline_data = Some(pair.value().clone()),
+                _ => (),
+            }
+        }
+        let inode = match (stat, times, atime) {
+            (Some(stat), Some(times), Some(atime)) => {
+                Inode::assemble(stat, times, atime, inline_data)
+            }
+            _ => return Err(FsError::InodeNotFound { inode: ino }),
+        };
+        self.stored.lock().unwrap().insert(ino, inode.clone());
+        Ok(inode)
     }
 
     pub async fn save_inode(&mut self, inode: &Inode) -> Result<()> {
-        let key = ScopedKey::inode(inode.ino);
+        if inode.nlink == 0 {
+            if !self.is_opened(inode.ino).await? {
+                return self.reclaim_inode(inode).await;
+            }
+            // keeping an unlinked inode relies on conflicting with any close which finds
+            // it not opened, even if nothing of the inode is changed
+            self.put_inode(inode).await?;
+            return self.touch_inode(inode.ino).await;
+        }
+        self.put_inode(inode).await
+    }
 
-        if inode.nlink == 0 && inode.opened_fh == 0 {
-            self.delete(key).await?;
-        } else {
-            self.put(key, inode.serialize()?).await?;
-            debug!("save inode: {:?}", inode);
+    /// Write the keys of an inode whose values are changed, so that transactions changing
+    /// different parts of an inode never conflict.
+    ///
+    /// An unchanged inode writes no key at all, so a transaction relying on a write conflict
+    /// with others reading the inode has to touch it.
+    async fn put_inode(&mut self, inode: &Inode) -> Result<()> {
+        let cached = self.stored.lock().unwrap().remove(&inode.ino);
+        let stored = match cached {
+            Some(stored) => Some(stored),
+            None => match self.read_inode_unchecked(inode.ino).await {
+                Ok(stored) => Some(stored),
+                Err(FsError::InodeNotFound { inode: _ }) => None,
+                Err(err) => return Err(err),
+            },
+        };
+
+        let stat = inode.stat();
+        if stored.as_ref().map_or(true, |stored| stored.stat() != stat) {
+            self.put(ScopedKey::inode(inode.ino), stat.serialize()?)
+                .await?;
         }
+        let times = inode.times();
+        if stored
+            .as_ref()
+            .map_or(true, |stored| stored.times() != times)
+        {
+            self.put(ScopedKey::times(inode.ino), times.serialize()?)
+                .await?;
+        }
+        let atime = inode.access_time();
+        if stored
+            .as_ref()
+            .map_or(true, |stored| stored.access_time() != atime)
+        {
+            self.put(ScopedKey::atime(inode.ino), atime.serialize()?)
+                .await?;
+        }
+        if stored.and_then(|stored| stored.inline_data) != inode.inline_data {
+            match &inode.inline_data {
+                Some(data) => self.put(ScopedKey::inline(inode.ino), data.clone()).await?,
+                None => self.delete(ScopedKey::inline(inode.ino)).await?,
+            }
+        }
+        self.stored.lock().unwrap().insert(inode.ino, inode.clone());
+        debug!("save inode: {:?}", inode);
         Ok(())
     }
 
     pub async fn remove_inode(&mut self, ino: u64) -> Result<()> {
+        self.clear_xattrs(ino).await?;
+        self.delete(ScopedKey::lock(ino)).await?;
+        self.delete(ScopedKey::inline(ino)).await?;
+        self.delete(ScopedKey::atime(ino)).await?;
+        self.delete(ScopedKey::times(ino)).await?;
         self.delete(ScopedKey::inode(ino)).await?;
+        self.stored.lock().unwrap().remove(&ino);
         Ok(())
     }
 
+    async fn reclaim_inode(&mut self, inode: &Inode) -> Result<()> {
+        debug!("reclaim inode({})", inode.ino);
+        self.charge(
+            inode,
+            -Self::quota_bytes(inode),
+            -Self::usage_blocks(inode),
+            -1,
+        )
+        .await?;
+        let end_block = (inode.size + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
+        if end_block > TiFs::MAX_TXN_BLOCKS {
+            let mut inode = inode.clone();
+            self.set_intent(&mut inode, IntentKind::Remove, 0, end_block);
+            return self.put_inode(&inode).await;
+        }
+
+        self.clear_blocks(inode).await?;
+        self.remove_inode(inode.ino).await
+    }
+
+    fn set_intent(&mut self, inode: &mut Inode, kind: IntentKind, size: u64, end_block: u64) {
+        inode.intent = Some(Intent::new(kind, size, end_block, self.clock));
+        if !self.intents.contains(&inode.ino) {
+            self.intents.push(inode.ino);
+        }
+    }
+
+    /// Apply a bounded step of the pending intent of an inode, return true if nothing is left.
+    ///
+    /// Truncations, removals, sharing, tree walks and finished writes are completed, while an
+    /// expired write is rolled back by deleting the blocks it has staged.
+    pub async fn step_intent(&mut self, ino: u64) -> Result<bool> {
+        let mut inode = match self.read_inode_unchecked(ino).await {
+            Err(FsError::InodeNotFound { inode: _ }) => return Ok(true),
+            result => result?,
+        };
+        let mut intent = match inode.intent.take() {
+            Some(intent) if !intent.busy(self.clock) => intent,
+            _ => return Ok(true),
+        };
+        match intent.kind {
+            IntentKind::Share => return self.step_share(ino, intent.target).await,
+            IntentKind::Lend => return self.step_share(intent.target, ino).await,
+            IntentKind::Clone | IntentKind::Empty => return self.step_tree(ino, intent).await,
+            IntentKind::Write | IntentKind::Commit => {
+                return self.step_staged(inode, intent).await;
+            }
+            _ => (),
+        }
+
+        let first_block = (intent.size + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
+        let from = first_block.max(intent.end_block.saturating_sub(TiFs::MAX_TXN_BLOCKS));
+        // a removed inode has been uncharged as a whole
+        if intent.kind != IntentKind::Remove {
+            let freed = self
+                .allocated_blocks(&inode, from..intent.end_block)
+                .await?
+                .len();
+            self.allocate(&mut inode, -(freed as i64)).await?;
+        }
+        for block in from..intent.end_block {
+            if inode.cow {
+                self.detach_block(ino, block).await?;
+            }
+            self.delete(ScopedKey::block(ino, block)).await?;
+        }
+        self.clear_deltas(ino, from..intent.end_block).await?;
+
+        if from > first_block {
+            debug!("intent {:?} of inode({}) is in progress", &intent, ino);
+            intent.end_block = from;
+            intent.renew(self.clock);
+            inode.intent = Some(intent);
+            self.put_inode(&inode).await?;
+            return Ok(false);
+        }
+
+        debug!("intent {:?} of inode({}) is done", &intent, ino);
+        if intent.kind == IntentKind::Remove {
+            self.remove_inode(ino).await?;
+            return Ok(true);
+        }
+
+        let tail = (intent.size % TiFs::BLOCK_SIZE) as usize;
+        if tail != 0 {
+            let block = intent.size / TiFs::BLOCK_SIZE;
+            if let Some(mut value) = self.take_block(&inode, block).await? {
+                value.truncate(tail);
+                let blocks = self.put_block(ino, block, value, true).await?;
+                self.allocate(&mut inode, blocks).await?;
+            }
+        }
+        self.save_inode(&inode).await?;
+        Ok(true)
+    }
+
+    async fn alloc_ino(&mut self) -> Result<u64> {
+        if self.inodes.is_empty() {
+            self.inodes = self.lease_inodes().await?;
+        }
+        let ino = self.inodes.start;
+        self.inodes.start += 1;
+
+        debug!("get ino({})", ino);
+        Ok(ino)
+    }
+
+    /// Lease a range of inode numbers from the meta, so that only one of `TiFs::INODE_LEASE`
+    /// creations writes the meta. Numbers left in the range are lost if the mount crashes.
+    async fn lease_inodes(&mut self) -> Result<Range<u64>> {
+        let mut meta = self.read_meta().await?.unwrap_or_default();
+        let start = meta.inode_next;
+        meta.inode_next += TiFs::INODE_LEASE;
+        self.save_meta(&meta).await?;
+        self.leased = true;
+
+        debug!("lease inodes({}..{})", start, meta.inode_next);
+        Ok(start..meta.inode_next)
+    }
+
     pub async fn read_meta(&self) -> Result<Option<Meta>> {
         let opt_data = self.get(ScopedKey::meta()).await?;
         opt_data.map(|data| Meta::deserialize(&data)).transpose()
@@ -208,12 +752,9 @@ impl Txn {
 
     async fn transfer_inline_data_to_block(&mut self, inode: &mut Inode) -> Result<()> {
         debug_assert!(inode.size <= TiFs::INLINE_DATA_THRESHOLD);
-        let key = ScopedKey::block(inode.ino, 0);
-        let mut data = inode.inline_data.clone().unwrap();
-        data.resize(TiFs::BLOCK_SIZE as usize, 0);
-        self.put(key, data).await?;
-        inode.inline_data = None;
-        Ok(())
+        let data = inode.inline_data.take().unwrap();
+        let blocks = self.put_block(inode.ino, 0, data, false).await?;
+        self.allocate(inode, blocks).await
     }
 
     async fn write_inline_data(
@@ -235,22 +776,17 @@ impl Txn {
         }
         inlined[start..start + size].copy_from_slice(data);
 
-        inode.atime = SystemTime::now();
-        inode.mtime = SystemTime::now();
-        inode.ctime = SystemTime::now();
+        let old_size = inode.size;
+        inode.touch_mtime();
         inode.set_size(inlined.len() as u64);
         inode.inline_data = Some(inlined);
+        self.charge_size(inode, old_size).await?;
         self.save_inode(inode).await?;
 
         Ok(size)
     }
 
-    async fn read_inline_data(
-        &mut self,
-        inode: &mut Inode,
-        start: u64,
-        size: u64,
-    ) -> Result<Vec<u8>> {
+    async fn read_inline_data(&self, inode: &Inode, start: u64, size: u64) -> Result<Vec<u8>> {
         debug_assert!(inode.size <= TiFs::INLINE_DATA_THRESHOLD);
 
         let start = start as usize;
@@ -265,19 +801,26 @@ impl Txn {
             data[..to_copy].copy_from_slice(&inlined[start..start + to_copy]);
         }
 
-        inode.atime = SystemTime::now();
-        self.save_inode(inode).await?;
-
         Ok(data)
     }
 
+    /// Bump the atime after a read, if the policy still asks for it.
+    /// Update the access time, skipped for an inode with an intent rather than waiting for it.
+    pub async fn update_atime(&mut self, ino: u64, atime: AtimePolicy) -> Result<()> {
+        let mut inode = self.read_inode_unchecked(ino).await?;
+        if inode.intent.is_none() && atime.need_update(&inode) {
+            inode.atime = SystemTime::now();
+            self.save_inode(&inode).await?;
+        }
+        Ok(())
+    }
+
     pub async fn read_data(
-        &mut self,
-        ino: u64,
+        &self,
+        attr: &Inode,
         start: u64,
         chunk_size: Option<u64>,
     ) -> Result<Vec<u8>> {
-        let mut attr = self.read_inode(ino).await?;
         if start >= attr.size {
             return Ok(Vec::new());
         }
@@ -286,124 +829,448 @@ impl Txn {
         let size = chunk_size.unwrap_or(max_size).min(max_size);
 
         if attr.inline_data.is_some() {
-            return self.read_inline_data(&mut attr, start, size).await;
+            self.read_inline_data(attr, start, size).await
+        } else {
+            self.read_blocks(attr, start, size).await
         }
+    }
 
+    async fn read_blocks(&self, attr: &Inode, start: u64, size: u64) -> Result<Vec<u8>> {
+        let ino = attr.ino;
         let target = start + size;
         let start_block = start / TiFs::BLOCK_SIZE;
         let end_block = (target + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
 
-        let pairs = self
+        let mut blocks: BTreeMap<u64, Vec<u8>> = self
             .scan(
                 ScopedKey::block_range(ino, start_block..end_block),
                 (end_block - start_block) as u32,
             )
-            .await?;
+            .await?
+            .map(|pair| (Self::block_index(pair.key()), pair.into_value()))
+            .collect();
+
+        if attr.cow {
+            let links: Vec<_> = self
+                .scan(
+                    ScopedKey::block_link_range(ino, start_block..end_block),
+                    (end_block - start_block) as u32,
+                )
+                .await?
+                .collect();
+            for pair in links {
+                let block = Self::block_index(pair.key());
+                let owner = BlockLink::deserialize(pair.value())?.owner;
+                if let Some(value) = self.get(ScopedKey::block(owner, block)).await? {
+                    blocks.insert(block, value);
+                }
+            }
+        }
 
-        let mut data = pairs
-            .enumerate()
-            .flat_map(|(i, pair)| {
-                let key = if let Ok(ScopedKey::Block { ino: _, block }) =
-                    ScopedKey::parse(pair.key().into())
-                {
-                    block
-                } else {
-                    unreachable!("the keys from scanning should be always valid block keys")
-                };
-                let value = pair.into_value();
-                (start_block as usize + i..key as usize)
-                    .map(|_| empty_block())
-                    .chain(vec![value])
-            })
-            .enumerate()
-            .fold(
-                Vec::with_capacity(
-                    ((end_block - start_block) * TiFs::BLOCK_SIZE - start % TiFs::BLOCK_SIZE)
-                        as usize,
-                ),
-                |mut data, (i, value)| {
-                    let mut slice = value.as_slice();
-                    if i == 0 {
-                        slice = &slice[(start % TiFs::BLOCK_SIZE) as usize..]
-                    }
+        // deltas of a block which is not stored are stale, see `fold_deltas`
+        for (block, _, delta) in self.read_deltas(ino, start_block..end_block).await? {
+            if let Some(value) = blocks.get_mut(&block) {
+                delta.apply(value);
+            }
+        }
 
-                    data.extend_from_slice(slice);
-                    data
-                },
-            );
+        let mut data = Vec::with_capacity(
+            ((end_block - start_block) * TiFs::BLOCK_SIZE - start % TiFs::BLOCK_SIZE) as usize,
+        );
+        for block in start_block..end_block {
+            let mut value = blocks.remove(&block).unwrap_or_else(empty_block);
+            value.resize(TiFs::BLOCK_SIZE as usize, 0);
+            let offset = if block == start_block {
+                (start % TiFs::BLOCK_SIZE) as usize
+            } else {
+                0
+            };
+            data.extend_from_slice(&value[offset..]);
+        }
 
         data.resize(size as usize, 0);
-        attr.atime = SystemTime::now();
-        self.save_inode(&attr).await?;
         Ok(data)
     }
 
-    pub async fn clear_data(&mut self, ino: u64) -> Result<u64> {
-        let mut attr = self.read_inode(ino).await?;
-        let end_block = (attr.size + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
+    /// Find the next data, or the next hole if `hole` is true, from `offset` of a file.
+    ///
+    /// Blocks which are not stored are holes, and so is the end of file. Inline data and
+    /// stored blocks are data as a whole, even if they end with zeros.
+    pub async fn seek_data(&self, inode: &Inode, offset: u64, hole: bool) -> Result<u64> {
+        if offset >= inode.size {
+            return Err(FsError::NoSuchOffset {
+                ino: inode.ino,
+                offset: offset as i64,
+            });
+        }
+        if inode.inline_data.is_some() {
+            return Ok(if hole { inode.size } else { offset });
+        }
 
-        for block in 0..end_block {
-            self.delete(ScopedKey::block(ino, block)).await?;
+        let end_block = (inode.size + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
+        let mut start_block = offset / TiFs::BLOCK_SIZE;
+        while start_block < end_block {
+            let blocks = start_block..end_block.min(start_block + TiFs::SCAN_LIMIT as u64);
+            let allocated = self.allocated_blocks(inode, blocks.clone()).await?;
+            if let Some(block) = blocks
+                .clone()
+                .find(|block| allocated.contains(block) != hole)
+            {
+                return Ok((block * TiFs::BLOCK_SIZE).max(offset).min(inode.size));
+            }
+            start_block = blocks.end;
+        }
+
+        if hole {
+            Ok(inode.size)
+        } else {
+            Err(FsError::NoSuchOffset {
+                ino: inode.ino,
+                offset: offset as i64,
+            })
         }
+    }
 
+    pub async fn clear_data(&mut self, ino: u64) -> Result<u64> {
+        let mut attr = self.read_inode(ino).await?;
         let clear_size = attr.size;
-        attr.size = 0;
-        attr.atime = SystemTime::now();
+        self.truncate(&mut attr, 0).await?;
         self.save_inode(&attr).await?;
         Ok(clear_size)
     }
 
-    pub async fn write_data(&mut self, ino: u64, start: u64, data: Bytes) -> Result<usize> {
-        debug!("write data at ({})[{}]", ino, start);
-        let mut inode = self.read_inode(ino).await?;
-        let size = data.len();
-        let target = start + size as u64;
-
-        if inode.inline_data.is_some() && target > TiFs::INLINE_DATA_THRESHOLD {
-            self.transfer_inline_data_to_block(&mut inode).await?;
+    /// Change the size of a file, deleting blocks beyond the new size after committing.
+    pub async fn truncate(&mut self, inode: &mut Inode, size: u64) -> Result<()> {
+        let old_size = inode.size;
+        if size != old_size {
+            inode.touch_mtime();
         }
-
-        if (inode.inline_data.is_some() || inode.size == 0) && target <= TiFs::INLINE_DATA_THRESHOLD
-        {
-            return self.write_inline_data(&mut inode, start, &data).await;
+        if inode.inline_data.is_some() {
+            if size <= TiFs::INLINE_DATA_THRESHOLD {
+                inode.inline_data.as_mut().unwrap().resize(size as usize, 0);
+                inode.set_size(size);
+                return self.charge_size(inode, old_size).await;
+            }
+            self.transfer_inline_data_to_block(inode).await?;
         }
 
-        let mut block_index = start / TiFs::BLOCK_SIZE;
-        let start_key = ScopedKey::block(ino, block_index);
-        let start_index = (start % TiFs::BLOCK_SIZE) as usize;
+        let end_block = (inode.size + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
+        if size < inode.size {
+            self.set_intent(inode, IntentKind::Truncate, size, end_block);
+        }
+        inode.set_size(size);
+        self.charge_size(inode, old_size).await
+    }
 
-        let first_block_size = TiFs::BLOCK_SIZE as usize - start_index;
+    /// Start a write which is too large for one transaction, return the start offset.
+    ///
+    /// Chunks of the write are staged aside, so the file is unchanged until it is finished.
+    pub async fn begin_write(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        handler: &FileHandler,
+        offset: i64,
+        size: u64,
+    ) -> Result<u64> {
+        let start = self.write_start(ino, fh, handler, offset).await?;
+        let mut inode = self.read_inode(ino).await?;
+        if inode.inline_data.is_some() {
+            self.transfer_inline_data_to_block(&mut inode).await?;
+        }
 
-        let (first_block, mut rest) = data.split_at(first_block_size.min(data.len()));
+        let end_block = (start + size + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
+        inode.intent = Some(Intent::new(IntentKind::Write, start, end_block, self.clock));
+        self.save_inode(&inode).await?;
+        Ok(start)
+    }
 
-        let mut start_value = self.get(start_key).await?.unwrap_or_else(empty_block);
+    /// Stage a chunk of a write as whole blocks, on top of blocks staged by previous chunks.
+    pub async fn write_chunk(&mut self, ino: u64, start: u64, data: Bytes) -> Result<()> {
+        let mut inode = self.read_inode_unchecked(ino).await?;
+        match inode.intent.as_mut() {
+            Some(intent) if intent.kind == IntentKind::Write => intent.renew(self.clock),
+            _ => return Err(FsError::IntentLost { ino }),
+        }
+
+        let mut offset = start;
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let block = offset / TiFs::BLOCK_SIZE;
+            let block_start = (offset % TiFs::BLOCK_SIZE) as usize;
+            let (current, next) =
+                rest.split_at((TiFs::BLOCK_SIZE as usize - block_start).min(rest.len()));
+            let key = ScopedKey::staged(ino, block);
+            let mut value = match self.get(key).await? {
+                Some(value) => value,
+                None => {
+                    self.read_blocks(&inode, block * TiFs::BLOCK_SIZE, TiFs::BLOCK_SIZE)
+                        .await?
+                }
+            };
+            value[block_start..block_start + current.len()].copy_from_slice(current);
+            self.put(key, value).await?;
+            offset += current.len() as u64;
+            rest = next;
+        }
+        self.put_inode(&inode).await
+    }
+
+    /// Finish a write, whose staged blocks are swapped in after committing.
+    ///
+    /// Any change of quotas or capacity is checked here, so swapping blocks never fails on it.
+    pub async fn finish_write(&mut self, ino: u64, target: u64) -> Result<()> {
+        let mut inode = self.read_inode_unchecked(ino).await?;
+        let mut intent = match inode.intent.take() {
+            Some(intent) if intent.kind == IntentKind::Write => intent,
+            _ => return Err(FsError::IntentLost { ino }),
+        };
+
+        let blocks = intent.size / TiFs::BLOCK_SIZE..intent.end_block;
+        let limit = (blocks.end - blocks.start) as u32;
+        let allocated = self.allocated_blocks(&inode, blocks.clone()).await?;
+        let new_blocks = self
+            .scan_keys(ScopedKey::staged_range(ino, blocks), limit)
+            .await?
+            .filter(|key| !allocated.contains(&Self::block_index(key)))
+            .count();
+        self.check_capacity(new_blocks as i64).await?;
+
+        let old_size = inode.size;
+        inode.touch_mtime();
+        inode.set_size(inode.size.max(target));
+        self.charge_size(&inode, old_size).await?;
+
+        intent.kind = IntentKind::Commit;
+        intent.renew(self.clock);
+        inode.intent = Some(intent);
+        self.intents.push(ino);
+        self.save_inode(&inode).await
+    }
+
+    /// Give up a write, whose staged blocks are discarded after committing.
+    pub async fn abort_write(&mut self, ino: u64) -> Result<()> {
+        let mut inode = self.read_inode_unchecked(ino).await?;
+        match inode.intent.as_mut() {
+            Some(intent) if intent.kind == IntentKind::Write => intent.expire(),
+            _ => return Ok(()),
+        }
+        self.intents.push(ino);
+        self.put_inode(&inode).await
+    }
+
+    /// Swap in a batch of staged blocks of a finished write, or discard them for an expired one.
+    async fn step_staged(&mut self, mut inode: Inode, mut intent: Intent) -> Result<bool> {
+        let ino = inode.ino;
+        let commit = intent.kind == IntentKind::Commit;
+        let staged: Vec<_> = self
+            .scan(
+                ScopedKey::staged_range(ino, intent.size / TiFs::BLOCK_SIZE..intent.end_block),
+                TiFs::MAX_TXN_BLOCKS as u32,
+            )
+            .await?
+            .collect();
+
+        if commit && !staged.is_empty() {
+            let first = Self::block_index(staged[0].key());
+            let last = Self::block_index(staged[staged.len() - 1].key());
+            let allocated = self.allocated_blocks(&inode, first..last + 1).await?;
+            let mut blocks = 0;
+            for pair in &staged {
+                let block = Self::block_index(pair.key());
+                if inode.cow {
+                    self.detach_block(ino, block).await?;
+                }
+                self.clear_deltas(ino, block..block + 1).await?;
+                blocks += self
+                    .put_block(ino, block, pair.value().clone(), allocated.contains(&block))
+                    .await?;
+            }
+            // the capacity has been checked when the write was finished
+            inode.allocated = (inode.allocated as i64 + blocks) as u64;
+            inode.update_blocks();
+            self.record_usage(ino, blocks, 0).await?;
+        }
+        for pair in &staged {
+            self.delete(pair.key().clone()).await?;
+        }
+
+        if staged.len() == TiFs::MAX_TXN_BLOCKS as usize {
+            debug!("intent {:?} of inode({}) is in progress", &intent, ino);
+            intent.renew(self.clock);
+            inode.intent = Some(intent);
+            self.put_inode(&inode).await?;
+            return Ok(false);
+        }
+
+        debug!("intent {:?} of inode({}) is done", &intent, ino);
+        self.save_inode(&inode).await?;
+        Ok(true)
+    }
+
+    pub async fn write_data(&mut self, ino: u64, start: u64, data: Bytes) -> Result<usize> {
+        debug!("write data at ({})[{}]", ino, start);
+        let mut inode = self.read_inode(ino).await?;
+        let size = data.len();
+        let target = start + size as u64;
+
+        if inode.inline_data.is_some() && target > TiFs::INLINE_DATA_THRESHOLD {
+            self.transfer_inline_data_to_block(&mut inode).await?;
+        }
+
+        if (inode.inline_data.is_some() || inode.size == 0) && target <= TiFs::INLINE_DATA_THRESHOLD
+        {
+            return self.write_inline_data(&mut inode, start, &data).await;
+        }
+
+        let block = start / TiFs::BLOCK_SIZE;
+        // deltas are only written to allocated blocks, so that they never allocate a block
+        let delta = size != 0
+            && size as u64 <= TiFs::DELTA_THRESHOLD
+            && block == (target - 1) / TiFs::BLOCK_SIZE
+            && !self
+                .allocated_blocks(&inode, block..block + 1)
+                .await?
+                .is_empty();
+        if delta {
+            self.write_delta(&mut inode, start, &data).await?;
+        } else {
+            self.write_blocks(&mut inode, start, &data).await?;
+        }
+
+        let old_size = inode.size;
+        // small writes within a file keep an mtime younger than `TiFs::MTIME_GRANULARITY`,
+        // so it may lag behind the last write by that much unlike POSIX requires; an mtime
+        // in the future is never kept, and the ctime is updated anyway
+        let recent = SystemTime::now()
+            .duration_since(inode.mtime)
+            .map_or(false, |elapsed| elapsed < TiFs::MTIME_GRANULARITY);
+        if delta && target <= old_size && recent {
+            inode.ctime = SystemTime::now();
+        } else {
+            inode.touch_mtime();
+        }
+        inode.set_size(inode.size.max(target));
+        self.charge_size(&inode, old_size).await?;
+        self.save_inode(&inode.into()).await?;
+        trace!("write data: {}", String::from_utf8_lossy(&data));
+        Ok(size)
+    }
+
+    async fn write_blocks(&mut self, inode: &mut Inode, start: u64, data: &[u8]) -> Result<()> {
+        let ino = inode.ino;
+        let mut block_index = start / TiFs::BLOCK_SIZE;
+        let end_block = (start + data.len() as u64 + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
+        let allocated = self
+            .allocated_blocks(inode, block_index..end_block.max(block_index + 1))
+            .await?;
+        let start_index = (start % TiFs::BLOCK_SIZE) as usize;
+
+        let first_block_size = TiFs::BLOCK_SIZE as usize - start_index;
+
+        let (first_block, mut rest) = data.split_at(first_block_size.min(data.len()));
+
+        let mut start_value = self
+            .take_block(inode, block_index)
+            .await?
+            .unwrap_or_else(empty_block);
 
         start_value[start_index..start_index + first_block.len()].copy_from_slice(first_block);
 
-        self.put(start_key, start_value).await?;
+        let mut blocks = self
+            .put_block(
+                ino,
+                block_index,
+                start_value,
+                allocated.contains(&block_index),
+            )
+            .await?;
 
         while rest.len() != 0 {
             block_index += 1;
-            let key = ScopedKey::block(ino, block_index);
             let (curent_block, current_rest) =
                 rest.split_at((TiFs::BLOCK_SIZE as usize).min(rest.len()));
             let mut value = curent_block.to_vec();
             if value.len() < TiFs::BLOCK_SIZE as usize {
-                let mut last_value = self.get(key).await?.unwrap_or_else(empty_block);
+                let mut last_value = self
+                    .take_block(inode, block_index)
+                    .await?
+                    .unwrap_or_else(empty_block);
                 last_value[..value.len()].copy_from_slice(&value);
                 value = last_value;
+            } else {
+                if inode.cow {
+                    self.detach_block(ino, block_index).await?;
+                }
+                self.clear_deltas(ino, block_index..block_index + 1).await?;
             }
-            self.put(key, value).await?;
+            blocks += self
+                .put_block(ino, block_index, value, allocated.contains(&block_index))
+                .await?;
             rest = current_rest;
         }
+        self.allocate(inode, blocks).await
+    }
 
-        inode.atime = SystemTime::now();
-        inode.mtime = SystemTime::now();
-        inode.ctime = SystemTime::now();
-        inode.set_size(inode.size.max(target));
-        self.save_inode(&inode.into()).await?;
-        trace!("write data: {}", String::from_utf8_lossy(&data));
-        Ok(size)
+    /// Store a write within one block as a delta of the block, instead of rewriting the block.
+    ///
+    /// The deltas are folded into the block once there are `TiFs::MAX_BLOCK_DELTAS` of them,
+    /// and the block is queued for compaction once there are `TiFs::COMPACT_DELTAS`.
+    async fn write_delta(&mut self, inode: &mut Inode, start: u64, data: &[u8]) -> Result<()> {
+        let ino = inode.ino;
+        let block = start / TiFs::BLOCK_SIZE;
+        let delta = BlockDelta::new((start % TiFs::BLOCK_SIZE) as usize, data.to_vec());
+        let seqs: Vec<_> = self
+            .scan_keys(
+                ScopedKey::delta_range(ino, block..block + 1),
+                TiFs::MAX_BLOCK_DELTAS as u32,
+            )
+            .await?
+            .map(|key| Self::delta_index(&key).1)
+            .collect();
+
+        // a delta is numbered by the timestamp of its transaction, so that concurrent writers
+        // never conflict on it, and a second delta of a block in a transaction is folded
+        let seq = match self.timestamp {
+            Some(seq) if !seqs.contains(&seq) && (seqs.len() as u64) < TiFs::MAX_BLOCK_DELTAS => {
+                seq
+            }
+            _ => {
+                debug!("fold deltas into block(<{}>[{}])", ino, block);
+                let mut value = self
+                    .take_block(inode, block)
+                    .await?
+                    .unwrap_or_else(empty_block);
+                delta.apply(&mut value);
+                let blocks = self.put_block(ino, block, value, true).await?;
+                return self.allocate(inode, blocks).await;
+            }
+        };
+        self.put(ScopedKey::delta(ino, block, seq), delta.encode())
+            .await?;
+        if seqs.len() as u64 + 1 >= TiFs::COMPACT_DELTAS
+            && !self.compactions.contains(&(ino, block))
+        {
+            self.compactions.push((ino, block));
+        }
+        Ok(())
+    }
+
+    /// Fold the deltas of a block into the block, if the inode and the deltas still exist.
+    pub async fn compact_block(&mut self, ino: u64, block: u64) -> Result<()> {
+        let mut inode = match self.read_inode(ino).await {
+            Err(FsError::InodeNotFound { inode: _ }) => return Ok(()),
+            result => result?,
+        };
+        if let Some(value) = self.fold_deltas(&inode, block).await? {
+            debug!("compact block(<{}>[{}])", ino, block);
+            let blocks = self.put_block(ino, block, value, true).await?;
+            if blocks != 0 {
+                self.allocate(&mut inode, blocks).await?;
+                self.save_inode(&inode).await?;
+            }
+        }
+        Ok(())
     }
 
     pub async fn write_link(&mut self, inode: &mut Inode, data: Bytes) -> Result<usize> {
@@ -413,14 +1280,18 @@ impl Txn {
         self.write_inline_data(inode, 0, &data).await
     }
 
-    pub async fn read_link(&mut self, ino: u64) -> Result<Vec<u8>> {
-        let mut inode = self.read_inode(ino).await?;
+    pub async fn read_link(&self, inode: &Inode) -> Result<Vec<u8>> {
         debug_assert!(inode.file_attr.kind == FileType::Symlink);
-        let size = inode.size;
-        self.read_inline_data(&mut inode, 0, size).await
+        self.read_inline_data(inode, 0, inode.size).await
     }
 
+    /// Make a hard link, which is not allowed for directories.
     pub async fn link(&mut self, ino: u64, newparent: u64, newname: ByteString) -> Result<Inode> {
+        let inode = self.read_inode(ino).await?;
+        if inode.kind == FileType::Directory {
+            return Err(FsError::NotPermitted { ino });
+        }
+        Self::check_writable(&inode)?;
         if let Some(old_ino) = self.get_index(newparent, newname.clone()).await? {
             let inode = self.read_inode(old_ino).await?;
             match inode.kind {
@@ -428,18 +1299,9 @@ impl Txn {
                 _ => self.unlink(newparent, newname.clone()).await?,
             }
         }
-        self.set_index(newparent, newname.clone(), ino).await?;
 
         let mut inode = self.read_inode(ino).await?;
-        let mut dir = self.read_dir(newparent).await?;
-
-        dir.push(DirItem {
-            ino,
-            name: newname.to_string(),
-            typ: inode.kind,
-        });
-
-        self.save_dir(newparent, &dir).await?;
+        self.add_entry(newparent, newname, ino, inode.kind).await?;
         inode.nlink += 1;
         inode.ctime = SystemTime::now();
         self.save_inode(&inode).await?;
@@ -447,55 +1309,154 @@ impl Txn {
     }
 
     pub async fn unlink(&mut self, parent: u64, name: ByteString) -> Result<()> {
-        match self.get_index(parent, name.clone()).await? {
-            None => Err(FsError::FileNotFound {
-                file: name.to_string(),
-            }),
-            Some(ino) => {
-                self.remove_index(parent, name.clone()).await?;
-                let parent_dir = self.read_dir(parent).await?;
-                let new_parent_dir: Directory = parent_dir
-                    .into_iter()
-                    .filter(|item| item.name != &*name)
-                    .collect();
-                self.save_dir(parent, &new_parent_dir).await?;
-
-                let mut inode = self.read_inode(ino).await?;
-                inode.nlink -= 1;
-                inode.ctime = SystemTime::now();
-                self.save_inode(&inode).await?;
-                Ok(())
-            }
-        }
+        let ino = self.remove_entry(parent, name).await?;
+        let mut inode = self.read_inode(ino).await?;
+        inode.nlink -= 1;
+        inode.ctime = SystemTime::now();
+        self.save_inode(&inode).await
     }
 
     pub async fn rmdir(&mut self, parent: u64, name: ByteString) -> Result<()> {
-        match self.get_index(parent, name.clone()).await? {
-            None => Err(FsError::FileNotFound {
+        let ino = self.lookup(parent, name.clone()).await?;
+        let mut inode = self.read_inode(ino).await?;
+        if inode.kind != FileType::Directory {
+            return Err(FsError::NotDirectory {
                 file: name.to_string(),
-            }),
-            Some(ino) => {
-                let target_dir = self.read_dir(ino).await?;
-                if target_dir.len() != 0 {
-                    let name_str = name.to_string();
-                    debug!("dir({}) not empty", &name_str);
-                    return Err(FsError::DirNotEmpty { dir: name_str });
+            });
+        }
+        let target_dir = self.read_dir(ino).await?;
+        if target_dir.len() != 0 {
+            let name_str = name.to_string();
+            debug!("dir({}) not empty", &name_str);
+            return Err(FsError::DirNotEmpty { dir: name_str });
+        }
+        self.remove_entry(parent, name).await?;
+
+        // the directory is reclaimed after the last handler is closed
+        inode.nlink = 0;
+        inode.ctime = SystemTime::now();
+        self.save_inode(&inode).await
+    }
+
+    /// Rename atomically, `flags` may be `RENAME_NOREPLACE` or `RENAME_EXCHANGE`.
+    pub async fn rename(
+        &mut self,
+        parent: u64,
+        name: ByteString,
+        newparent: u64,
+        newname: ByteString,
+        flags: u32,
+    ) -> Result<()> {
+        let noreplace = flags & RENAME_NOREPLACE as u32 != 0;
+        let exchange = flags & RENAME_EXCHANGE as u32 != 0;
+        if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) as u32 != 0 || (noreplace && exchange) {
+            return Err(FsError::UnknownRenameFlags { flags });
+        }
+
+        let ino = self.lookup(parent, name.clone()).await?;
+        let mut inode = self.read_inode(ino).await?;
+        let target = match self.get_index(newparent, newname.clone()).await? {
+            Some(target_ino) => Some(self.read_inode(target_ino).await?),
+            None if exchange => {
+                return Err(FsError::FileNotFound {
+                    file: newname.to_string(),
+                })
+            }
+            None => None,
+        };
+        if inode.kind == FileType::Directory {
+            self.check_subtree(ino, newparent).await?;
+        }
+        if parent != newparent {
+            self.check_project(&inode, parent, newparent).await?;
+            if let Some(target) = target.as_ref().filter(|_| exchange) {
+                self.check_project(target, newparent, parent).await?;
+            }
+        }
+
+        let now = SystemTime::now();
+        match target {
+            // both names refer to the same file, nothing to do
+            Some(target) if target.ino == ino => return Ok(()),
+            Some(_) if noreplace => {
+                return Err(FsError::FileExist {
+                    file: newname.to_string(),
+                })
+            }
+            Some(mut target) if exchange => {
+                if target.kind == FileType::Directory {
+                    self.check_subtree(target.ino, parent).await?;
                 }
-                self.remove_index(parent, name.clone()).await?;
-                self.remove_inode(ino).await?;
-
-                let parent_dir = self.read_dir(parent).await?;
-                let new_parent_dir: Directory = parent_dir
-                    .into_iter()
-                    .filter(|item| item.name != &*name)
-                    .collect();
-                self.save_dir(parent, &new_parent_dir).await?;
-                Ok(())
+                self.replace_entry(parent, name, target.ino, target.kind)
+                    .await?;
+                self.replace_entry(newparent, newname, ino, inode.kind)
+                    .await?;
+                target.ctime = now;
+                self.save_inode(&target).await?;
+            }
+            target => {
+                if let Some(target) = target {
+                    match (
+                        inode.kind == FileType::Directory,
+                        target.kind == FileType::Directory,
+                    ) {
+                        (true, false) => {
+                            return Err(FsError::NotDirectory {
+                                file: newname.to_string(),
+                            })
+                        }
+                        (false, true) => {
+                            return Err(FsError::IsDirectory {
+                                file: newname.to_string(),
+                            })
+                        }
+                        (true, true) => self.rmdir(newparent, newname.clone()).await?,
+                        (false, false) => self.unlink(newparent, newname.clone()).await?,
+                    }
+                }
+                self.remove_entry(parent, name).await?;
+                self.add_entry(newparent, newname, ino, inode.kind).await?;
+            }
+        }
+
+        inode.ctime = now;
+        self.save_inode(&inode).await
+    }
+
+    /// Fail with `EXDEV` if moving an inode from `parent` to `newparent` takes it out of
+    /// its project, as the usage of a moved file stays charged to its own project.
+    /// The root of a project tree can be moved between directories of the same project.
+    async fn check_project(&self, inode: &Inode, parent: u64, newparent: u64) -> Result<()> {
+        let project = self.read_inode(newparent).await?.project;
+        if inode.project != project && self.read_inode(parent).await?.project != project {
+            return Err(FsError::CrossProject {
+                ino: inode.ino,
+                project,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check that the directory `ino` is neither `parent` nor an ancestor of it.
+    async fn check_subtree(&self, ino: u64, parent: u64) -> Result<()> {
+        let mut current = parent;
+        loop {
+            if current == ino {
+                return Err(FsError::InvalidRename { ino, parent });
+            }
+            if current == ROOT_INODE {
+                return Ok(());
             }
+            current = self.read_parent(current).await?;
         }
     }
 
     pub async fn lookup(&self, parent: u64, name: ByteString) -> Result<u64> {
+        match &*name {
+            "." => return Ok(self.read_inode(parent).await?.ino),
+            PARENT_NAME => return self.read_parent(parent).await,
+            _ => (),
+        }
         self.get_index(parent, name.clone())
             .await?
             .ok_or_else(|| FsError::FileNotFound {
@@ -520,8 +1481,10 @@ impl Txn {
             }
         }
 
+        let old_size = inode.size;
         inode.set_size(target_size);
-        inode.mtime = SystemTime::now();
+        inode.touch_mtime();
+        self.charge_size(inode, old_size).await?;
         self.save_inode(inode).await?;
         Ok(())
     }
@@ -531,17 +1494,17 @@ impl Txn {
         parent: u64,
         name: ByteString,
         mode: u32,
-        gid: u32,
-        uid: u32,
+        cred: &Credentials,
+        umask: u32,
     ) -> Result<Inode> {
-        let dir_mode = make_mode(FileType::Directory, mode as _);
-        let mut inode = self.make_inode(parent, name, dir_mode, gid, uid, 0).await?;
-        inode.perm = mode as _;
-        self.save_inode(&inode).await?;
+        let dir_mode = make_mode(FileType::Directory, as_file_perm(mode));
+        let inode = self
+            .make_inode(parent, name, dir_mode, cred, 0, umask)
+            .await?;
         self.save_dir(inode.ino, &Directory::new()).await
     }
 
-    pub async fn read_dir(&mut self, ino: u64) -> Result<Directory> {
+    pub async fn read_dir(&self, ino: u64) -> Result<Directory> {
         let data =
             self.get(ScopedKey::block(ino, 0))
                 .await?
@@ -556,26 +1519,1680 @@ impl Txn {
     pub async fn save_dir(&mut self, ino: u64, dir: &Directory) -> Result<Inode> {
         let data = super::dir::encode(dir)?;
         let mut inode = self.read_inode(ino).await?;
+        inode.allocated = 1;
         inode.set_size(data.len() as u64);
-        inode.atime = SystemTime::now();
-        inode.mtime = SystemTime::now();
-        inode.ctime = SystemTime::now();
+        inode.touch_mtime();
         self.save_inode(&inode).await?;
         self.put(ScopedKey::block(ino, 0), data).await?;
         Ok(inode)
     }
-}
 
-impl Deref for Txn {
-    type Target = Transaction;
+    pub async fn get_xattr(&self, ino: u64, name: ByteString) -> Result<Vec<u8>> {
+        Self::check_xattr_name(&name)?;
+        self.read_inode(ino).await?;
+        self.get(ScopedKey::xattr(ino, &name))
+            .await?
+            .ok_or_else(|| FsError::XattrNotFound {
+                ino,
+                name: name.to_string(),
+            })
+    }
+
+    pub async fn set_xattr(
+        &mut self,
+        ino: u64,
+        name: ByteString,
+        value: Vec<u8>,
+        flags: i32,
+    ) -> Result<()> {
+        Self::check_xattr_name(&name)?;
+        if value.len() > TiFs::XATTR_SIZE_MAX {
+            return Err(FsError::XattrTooLarge { size: value.len() });
+        }
+        let mut inode = self.read_inode(ino).await?;
+        let key = ScopedKey::xattr(ino, &name);
+        let exist = self.get(key).await?.is_some();
+        if exist && flags & XATTR_CREATE != 0 {
+            return Err(FsError::XattrExist {
+                ino,
+                name: name.to_string(),
+            });
+        }
+        if !exist && flags & XATTR_REPLACE != 0 {
+            return Err(FsError::XattrNotFound {
+                ino,
+                name: name.to_string(),
+            });
+        }
+        match &*name {
+            ACL_ACCESS => {
+                let acl = Acl::parse(&value)?;
+                inode.perm = (inode.perm & !0o777) | acl.mode();
+                if acl.is_minimal() {
+                    self.delete(key).await?;
+                } else {
+                    self.put(key, acl.encode()).await?;
+                }
+            }
+            ACL_DEFAULT => {
+                if inode.kind != FileType::Directory {
+                    return Err(FsError::NotDirectory {
+                        file: ino.to_string(),
+                    });
+                }
+                self.put(key, Acl::parse(&value)?.encode()).await?;
+            }
+            _ => self.put(key, value).await?,
+        }
+        inode.ctime = SystemTime::now();
+        self.save_inode(&inode).await
+    }
+
+    pub async fn read_acl(&self, ino: u64, name: &str) -> Result<Option<Acl>> {
+        self.get(ScopedKey::xattr(ino, name))
+            .await?
+            .map(|data| Acl::parse(&data))
+            .transpose()
+    }
+
+    /// Keep the access acl in sync with the permission bits after a chmod.
+    pub async fn sync_acl(&mut self, inode: &Inode) -> Result<()> {
+        if let Some(mut acl) = self.read_acl(inode.ino, ACL_ACCESS).await? {
+            acl.set_mode(inode.perm & 0o777);
+            self.put(ScopedKey::xattr(inode.ino, ACL_ACCESS), acl.encode())
+                .await?;
+        }
+        Ok(())
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    pub async fn check_access(&self, ino: u64, cred: &Credentials, mask: i32) -> Result<()> {
+        if ino == SNAPSHOTS_INODE {
+            return if mask & W_OK != 0 {
+                Err(FsError::ReadOnly { ino })
+            } else {
+                Ok(())
+            };
+        }
+        let inode = self.read_inode(ino).await?;
+        if mask & W_OK != 0 {
+            Self::check_writable(&inode)?;
+        }
+        self.check_permission(&inode, cred, mask).await
+    }
+
+    /// Inodes of snapshots are never changed through the mount.
+    pub fn check_writable(inode: &Inode) -> Result<()> {
+        if inode.readonly {
+            Err(FsError::ReadOnly { ino: inode.ino })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check permissions of the caller by the access acl, or by the permission bits if there is not one.
+    pub async fn check_permission(
+        &self,
+        inode: &Inode,
+        cred: &Credentials,
+        mask: i32,
+    ) -> Result<()> {
+        if mask == F_OK {
+            return Ok(());
+        }
+        let acl = match self.read_acl(inode.ino, ACL_ACCESS).await? {
+            Some(acl) => acl,
+            None => Acl::from_mode(inode.perm),
+        };
+        if acl.permits(inode, cred, mask as u16) {
+            Ok(())
+        } else {
+            Err(FsError::PermissionDenied {
+                ino: inode.ino,
+                mask,
+            })
+        }
+    }
+
+    pub async fn read_locks(&self, ino: u64) -> Result<LockTable> {
+        self.get(ScopedKey::lock(ino))
+            .await?
+            .map(|data| LockTable::deserialize(&data))
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    async fn save_locks(&mut self, ino: u64, locks: &LockTable) -> Result<()> {
+        if locks.is_empty() {
+            self.delete(ScopedKey::lock(ino)).await?;
+        } else {
+            self.put(ScopedKey::lock(ino), locks.serialize()?).await?;
+        }
+        Ok(())
+    }
+
+    /// Find a lock conflicting with `lock`, locks of expired sessions are released on the way.
+    async fn live_conflict(
+        &self,
+        locks: &mut LockTable,
+        lock: &LockRange,
+        now: u64,
+    ) -> Result<Option<LockRange>> {
+        while let Some(conflict) = locks.conflict(lock).copied() {
+            if self.session_alive(conflict.session, now).await? {
+                return Ok(Some(conflict));
+            }
+            debug!("release locks of expired session({})", conflict.session);
+            locks.remove_session(conflict.session);
+        }
+        Ok(None)
+    }
+
+    /// Set a POSIX lock, failing with `LockConflict` if another owner holds a conflicting lock.
+    pub async fn set_lock(&mut self, ino: u64, lock: LockRange, now: u64) -> Result<()> {
+        if lock.start > lock.end {
+            return Err(FsError::InvalidLock);
+        }
+        if self.read_inode(ino).await?.kind == FileType::Directory {
+            return Err(FsError::InvalidLock);
+        }
+        let mut locks = self.read_locks(ino).await?;
+        if lock.typ != F_UNLCK {
+            if let Some(conflict) = self.live_conflict(&mut locks, &lock, now).await? {
+                return Err(FsError::LockConflict {
+                    ino,
+                    owner: conflict.owner,
+                });
+            }
+        }
+        locks.set(lock);
+        self.save_locks(ino, &locks).await
+    }
+
+    /// Find a lock conflicting with `lock`, return an unlocked range if there is none.
+    pub async fn get_lock(&self, ino: u64, lock: LockRange, now: u64) -> Result<LockRange> {
+        let mut locks = self.read_locks(ino).await?;
+        Ok(self
+            .live_conflict(&mut locks, &lock, now)
+            .await?
+            .unwrap_or(LockRange {
+                typ: F_UNLCK,
+                ..lock
+            }))
+    }
+
+    /// Release all locks of an owner, which is done when any file of the owner is closed.
+    pub async fn release_locks(&mut self, ino: u64, session: u64, owner: u64) -> Result<()> {
+        let mut locks = self.read_locks(ino).await?;
+        if locks.remove_owner(session, owner) {
+            self.save_locks(ino, &locks).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn read_session(&self, id: u64) -> Result<Option<Session>> {
+        self.get(ScopedKey::session(id))
+            .await?
+            .map(|data| Session::deserialize(&data))
+            .transpose()
+    }
+
+    async fn save_session(&mut self, session: &Session) -> Result<()> {
+        self.put(ScopedKey::session(session.id), session.serialize()?)
+            .await?;
+        Ok(())
+    }
+
+    /// Whether a session is registered and its lease has not expired.
+    pub async fn session_alive(&self, id: u64, now: u64) -> Result<bool> {
+        Ok(self
+            .read_session(id)
+            .await?
+            .map_or(false, |session| !session.expired(now)))
+    }
+
+    /// Register a session for a new mount.
+    pub async fn new_session(&mut self, now: u64) -> Result<Session> {
+        let mut meta = self.read_meta().await?.unwrap_or_default();
+        let session = Session::new(meta.session_next, now);
+        meta.session_next += 1;
+        self.save_meta(&meta).await?;
+        self.save_session(&session).await?;
+        debug!("new session({})", session.id);
+        Ok(session)
+    }
+
+    /// Renew the lease of a session.
+    ///
+    /// A session expired or removed may have lost what it held to other mounts,
+    /// so a new session is registered instead, which is to be compared by the caller.
+    /// An expired session with no opened inodes is removed on the way, others are left
+    /// to be reaped.
+    pub async fn renew_session(&mut self, id: u64, now: u64) -> Result<Session> {
+        match self.read_session(id).await? {
+            Some(mut session) if !session.expired(now) => {
+                session.renew(now);
+                self.save_session(&session).await?;
+                Ok(session)
+            }
+            Some(_) => {
+                let opened = self
+                    .scan_keys(ScopedKey::session_opened_range(id), 1)
+                    .await?
+                    .next()
+                    .is_some();
+                if !opened {
+                    self.remove_session(id).await?;
+                }
+                self.new_session(now).await
+            }
+            None => self.new_session(now).await,
+        }
+    }
+
+    pub async fn remove_session(&mut self, id: u64) -> Result<()> {
+        self.delete(ScopedKey::session(id)).await?;
+        Ok(())
+    }
+
+    /// Close a batch of inodes left opened by an expired session, remove the session once
+    /// all of them are closed. Return whether the session is removed.
+    ///
+    /// The session is read again in this transaction, so a session renewed since it was
+    /// listed is left alone, and the renewal conflicts with a concurrent reap.
+    pub async fn reap_session(&mut self, id: u64, now: u64) -> Result<bool> {
+        match self.read_session(id).await? {
+            Some(session) if session.expired(now) => (),
+            _ => return Ok(true),
+        }
+        let keys = self
+            .scan_keys(ScopedKey::session_opened_range(id), TiFs::SCAN_LIMIT)
+            .await?
+            .collect::<Vec<_>>();
+        for key in keys.iter() {
+            if let ScopedKey::SessionOpened { session: _, ino } = ScopedKey::parse(key.into())? {
+                debug!("close inode({}) opened by session({})", ino, id);
+                self.close(ino, id).await?;
+            }
+        }
+        if keys.len() < TiFs::SCAN_LIMIT as usize {
+            self.remove_session(id).await?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    pub async fn list_sessions(&self) -> Result<Vec<Session>> {
+        self.scan_all(ScopedKey::session_range())
+            .await?
+            .iter()
+            .map(|pair| Session::deserialize(pair.value()))
+            .collect()
+    }
+
+    /// The setuid bit, and the setgid bit if the group can execute the file, which are
+    /// cleared on writing or changing owners.
+    fn privileges(perm: u16) -> u16 {
+        let mut bits = S_ISUID as u16;
+        if perm & S_IXGRP as u16 != 0 {
+            bits |= S_ISGID as u16;
+        }
+        perm & bits
+    }
+
+    /// Check a chmod and return the permission bits to set.
+    ///
+    /// Anyone able to write the file may clear its privileges, as the kernel does on writing.
+    /// The setgid bit is dropped if the caller is not in the group of the file.
+    pub async fn check_chmod(&self, inode: &Inode, cred: &Credentials, perm: u16) -> Result<u16> {
+        let killed = perm != inode.perm && perm | Self::privileges(inode.perm) == inode.perm;
+        if killed {
+            self.check_access(inode.ino, cred, W_OK).await?;
+        } else {
+            Self::check_owner(inode, cred)?;
+        }
+        if perm & S_ISGID as u16 != 0 && !cred.is_root() && !cred.in_group(inode.gid) {
+            return Ok(perm & !S_ISGID as u16);
+        }
+        Ok(perm)
+    }
+
+    /// In a sticky directory, only the owner of an entry or the directory can remove or rename it.
+    pub async fn check_sticky(
+        &self,
+        parent: u64,
+        name: ByteString,
+        cred: &Credentials,
+    ) -> Result<()> {
+        if cred.is_root() {
+            return Ok(());
+        }
+        let dir = self.read_inode(parent).await?;
+        if dir.perm & S_ISVTX as u16 == 0 || dir.uid == cred.uid {
+            return Ok(());
+        }
+        match self.get_index(parent, name).await? {
+            Some(ino) if self.read_inode(ino).await?.uid != cred.uid => {
+                Err(FsError::NotPermitted { ino })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Only the owner or root can change the mode, timestamps or acls of an inode.
+    pub fn check_owner(inode: &Inode, cred: &Credentials) -> Result<()> {
+        if cred.is_root() || cred.uid == inode.uid {
+            Ok(())
+        } else {
+            Err(FsError::NotPermitted { ino: inode.ino })
+        }
+    }
+
+    /// Check permissions to change the owner, size or timestamps of an inode.
+    pub async fn check_setattr(
+        &self,
+        inode: &Inode,
+        cred: &Credentials,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        fh: Option<(u64, FileHandler)>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> Result<()> {
+        if cred.is_root() {
+            return Ok(());
+        }
+        if uid.map_or(false, |uid| uid != inode.uid) {
+            return Err(FsError::NotPermitted { ino: inode.ino });
+        }
+        if let Some(gid) = gid {
+            if gid != inode.gid && (cred.uid != inode.uid || !cred.in_group(gid)) {
+                return Err(FsError::NotPermitted { ino: inode.ino });
+            }
+        }
+
+        if size.is_some() {
+            match fh {
+                Some((fh, handler)) if !handler.writable() => {
+                    return Err(FsError::BadAccessMode {
+                        ino: inode.ino,
+                        fh,
+                        access: "writing",
+                    })
+                }
+                Some(_) => (),
+                None => self.check_permission(inode, cred, W_OK).await?,
+            }
+        }
+
+        let specific = |time: Option<TimeOrNow>| matches!(time, Some(TimeOrNow::SpecificTime(_)));
+        if specific(atime) || specific(mtime) {
+            Self::check_owner(inode, cred)?;
+        } else if (atime.is_some() || mtime.is_some()) && cred.uid != inode.uid {
+            // setting timestamps to now only requires write permission
+            self.check_permission(inode, cred, W_OK).await?;
+        }
+        Ok(())
+    }
+
+    /// Check permissions to access an extended attribute by its namespace.
+    pub async fn check_xattr_access(
+        &self,
+        ino: u64,
+        name: &str,
+        cred: &Credentials,
+        mask: i32,
+    ) -> Result<()> {
+        if ino == SNAPSHOTS_INODE && mask & W_OK != 0 {
+            return Err(FsError::ReadOnly { ino });
+        }
+        let inode = self.read_inode(ino).await?;
+        if mask & W_OK != 0 {
+            Self::check_writable(&inode)?;
+        }
+        if name == ACL_ACCESS || name == ACL_DEFAULT {
+            return if mask & W_OK != 0 {
+                Self::check_owner(&inode, cred)
+            } else {
+                Ok(())
+            };
+        }
+        if name.starts_with("trusted.") && !cred.is_root() {
+            return Err(FsError::NotPermitted { ino });
+        }
+        if name.starts_with("user.")
+            && inode.kind != FileType::RegularFile
+            && inode.kind != FileType::Directory
+        {
+            return Err(FsError::NotPermitted { ino });
+        }
+        self.check_permission(&inode, cred, mask).await
+    }
+
+    pub async fn remove_xattr(&mut self, ino: u64, name: ByteString) -> Result<()> {
+        Self::check_xattr_name(&name)?;
+        let mut inode = self.read_inode(ino).await?;
+        let key = ScopedKey::xattr(ino, &name);
+        if self.get(key).await?.is_none() {
+            return Err(FsError::XattrNotFound {
+                ino,
+                name: name.to_string(),
+            });
+        }
+        self.delete(key).await?;
+        inode.ctime = SystemTime::now();
+        self.save_inode(&inode).await
+    }
+
+    fn check_xattr_name(name: &str) -> Result<()> {
+        if name.len() > TiFs::XATTR_NAME_MAX {
+            return Err(FsError::XattrNameTooLong {
+                name: name.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// List names of extended attributes, each of which is terminated by a null byte.
+    pub async fn list_xattr(&self, ino: u64) -> Result<Vec<u8>> {
+        self.read_inode(ino).await?;
+        let mut names = Vec::new();
+        for key in self.scan_keys_all(ScopedKey::xattr_range(ino)).await? {
+            if let ScopedKey::Xattr { ino: _, name } = ScopedKey::parse((&key).into())? {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+        }
+        Ok(names)
+    }
+
+    async fn clear_xattrs(&mut self, ino: u64) -> Result<()> {
+        for key in self.scan_keys_all(ScopedKey::xattr_range(ino)).await? {
+            self.delete(key).await?;
+        }
+        Ok(())
+    }
+
+    async fn copy_xattrs(&mut self, src: u64, dst: u64) -> Result<()> {
+        for pair in self.scan_all(ScopedKey::xattr_range(src)).await? {
+            if let ScopedKey::Xattr { ino: _, name } = ScopedKey::parse(pair.key().into())? {
+                self.put(ScopedKey::xattr(dst, name), pair.value().clone())
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes of an inode charged to quotas, directories are charged as inodes only.
+    fn quota_bytes(inode: &Inode) -> i64 {
+        if inode.kind == FileType::Directory {
+            0
+        } else {
+            inode.size as i64
+        }
+    }
+
+    /// Blocks of an inode counted in the usage of the volume, directories are counted as files only.
+    fn usage_blocks(inode: &Inode) -> i64 {
+        if inode.kind == FileType::Directory {
+            0
+        } else {
+            inode.allocated as i64
+        }
+    }
+
+    /// Account blocks allocated or freed for an inode, in its attributes and the usage of the volume.
+    async fn allocate(&mut self, inode: &mut Inode, blocks: i64) -> Result<()> {
+        inode.allocated = (inode.allocated as i64 + blocks) as u64;
+        inode.update_blocks();
+        self.add_usage(inode.ino, blocks, 0).await
+    }
+
+    /// Charge a change of usage to the quotas of an inode and the usage counters of the volume.
+    async fn charge(&mut self, inode: &Inode, bytes: i64, blocks: i64, inodes: i64) -> Result<()> {
+        self.charge_quota(inode, bytes, inodes).await?;
+        self.add_usage(inode.ino, blocks, inodes).await
+    }
+
+    /// Charge a change of usage to the quotas of an inode, failing if a limit is exceeded.
+    ///
+    /// The change is added to a shard of each quota, the shards are summed up only if the
+    /// change is checked against a limit.
+    pub async fn charge_quota(&mut self, inode: &Inode, bytes: i64, inodes: i64) -> Result<()> {
+        if bytes == 0 && inodes == 0 {
+            return Ok(());
+        }
+        for (kind, id) in QuotaKind::of(inode) {
+            let quota = match self.get(ScopedKey::quota(kind, id)).await? {
+                Some(data) => Quota::deserialize(&data)?,
+                None => continue,
+            };
+            if quota.limits(bytes, inodes) {
+                self.read_quota_usage(quota).await?.check(bytes, inodes)?;
+            }
+            self.add_quota_usage(kind, id, inode.ino, QuotaUsage::new(bytes, inodes))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Charge the change of size of an inode, from `old_size` to the current one.
+    async fn charge_size(&mut self, inode: &Inode, old_size: u64) -> Result<()> {
+        if inode.kind == FileType::Directory {
+            return Ok(());
+        }
+        self.charge_quota(inode, inode.size as i64 - old_size as i64, 0)
+            .await
+    }
+
+    /// Add a change to a shard of the usage counters, failing if the capacity is exceeded.
+    ///
+    /// Shards are chosen by inode numbers, so creations on different mounts rarely conflict.
+    async fn add_usage(&mut self, ino: u64, blocks: i64, files: i64) -> Result<()> {
+        self.check_capacity(blocks).await?;
+        self.record_usage(ino, blocks, files).await
+    }
+
+    /// Fail with `ENOSPC` if allocating more blocks would exceed the capacity of the volume.
+    ///
+    /// The space cached by the mount is used if any, otherwise all shards are read.
+    async fn check_capacity(&self, blocks: i64) -> Result<()> {
+        if blocks <= 0 {
+            return Ok(());
+        }
+        let space = match self.space {
+            Some(space) => Space::new(space.capacity, space.blocks + self.allocated),
+            None => self.read_space().await?,
+        };
+        if space.exceeds(blocks, TiFs::BLOCK_SIZE) {
+            return Err(FsError::NoSpace);
+        }
+        Ok(())
+    }
+
+    /// Capacity and used blocks of the volume, the usage is read only if there is a capacity.
+    pub async fn read_space(&self) -> Result<Space> {
+        match self.read_meta().await?.and_then(|meta| meta.capacity) {
+            Some(capacity) => Ok(Space::new(Some(capacity), self.read_usage().await?.blocks)),
+            None => Ok(Space::default()),
+        }
+    }
+
+    /// Add a change to a shard of the usage counters without checking the capacity.
+    async fn record_usage(&mut self, ino: u64, blocks: i64, files: i64) -> Result<()> {
+        if blocks == 0 && files == 0 {
+            return Ok(());
+        }
+        self.allocated += blocks;
+        let key = ScopedKey::usage(ino % TiFs::USAGE_SHARDS);
+        let mut usage = self
+            .get(key)
+            .await?
+            .map(|data| Usage::deserialize(&data))
+            .transpose()?
+            .unwrap_or_default();
+        usage += Usage::new(blocks, files);
+        self.put(key, usage.serialize()?).await?;
+        Ok(())
+    }
+
+    /// Usage of the volume, summing up all shards.
+    pub async fn read_usage(&self) -> Result<Usage> {
+        self.scan(ScopedKey::usage_range(), TiFs::USAGE_SHARDS as u32)
+            .await?
+            .map(|pair| Usage::deserialize(pair.value()))
+            .try_fold(Usage::default(), |mut total, usage| {
+                total += usage?;
+                Ok(total)
+            })
+    }
+
+    /// Rebuild the usage counters by scanning all inodes.
+    pub async fn recount_usage(&mut self) -> Result<Usage> {
+        let mut total = Usage::default();
+        for inode in self.read_all_inodes().await? {
+            total += Usage::new(Self::usage_blocks(&inode), 1);
+        }
+        for shard in 0..TiFs::USAGE_SHARDS {
+            self.delete(ScopedKey::usage(shard)).await?;
+        }
+        self.put(ScopedKey::usage(0), total.serialize()?).await?;
+        Ok(total)
+    }
+
+    pub async fn set_capacity(&mut self, capacity: Option<u64>) -> Result<Meta> {
+        let mut meta = self.read_meta().await?.unwrap_or_default();
+        meta.capacity = capacity;
+        self.save_meta(&meta).await?;
+        Ok(meta)
+    }
+
+    /// Read all inodes, which is expensive and only used by administration.
+    async fn read_all_inodes(&self) -> Result<Vec<Inode>> {
+        let next_inode = self
+            .read_meta()
+            .await?
+            .map(|meta| meta.inode_next)
+            .unwrap_or(ROOT_INODE);
+        let stats = self
+            .scan(
+                ScopedKey::inode_range(ROOT_INODE..next_inode),
+                (next_inode - ROOT_INODE) as u32,
+            )
+            .await?
+            .map(|pair| Inode::deserialize(pair.value()))
+            .collect::<Result<Vec<_>>>()?;
+        let mut inodes = Vec::with_capacity(stats.len());
+        for stat in stats {
+            inodes.push(self.read_inode_unchecked(stat.ino).await?);
+        }
+        Ok(inodes)
+    }
+
+    pub async fn read_quota(&self, kind: QuotaKind, id: u32) -> Result<Option<Quota>> {
+        match self.get(ScopedKey::quota(kind, id)).await? {
+            Some(data) => Ok(Some(
+                self.read_quota_usage(Quota::deserialize(&data)?).await?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Fill in the usage of a quota, summing up all shards.
+    async fn read_quota_usage(&self, mut quota: Quota) -> Result<Quota> {
+        let mut total = QuotaUsage::default();
+        for pair in self
+            .scan(
+                ScopedKey::quota_usage_range(quota.kind, quota.id),
+                TiFs::USAGE_SHARDS as u32,
+            )
+            .await?
+        {
+            total += QuotaUsage::deserialize(pair.value())?;
+        }
+        quota.bytes = total.bytes.max(0) as u64;
+        quota.inodes = total.inodes.max(0) as u64;
+        Ok(quota)
+    }
+
+    /// Add a change to the shard of a quota chosen by the inode number.
+    async fn add_quota_usage(
+        &mut self,
+        kind: QuotaKind,
+        id: u32,
+        ino: u64,
+        change: QuotaUsage,
+    ) -> Result<()> {
+        let key = ScopedKey::quota_usage(kind, id, ino % TiFs::USAGE_SHARDS);
+        let mut usage = self
+            .get(key)
+            .await?
+            .map(|data| QuotaUsage::deserialize(&data))
+            .transpose()?
+            .unwrap_or_default();
+        usage += change;
+        self.put(key, usage.serialize()?).await?;
+        Ok(())
+    }
+
+    /// Delete all shards of the usage of a quota.
+    async fn clear_quota_usage(&mut self, kind: QuotaKind, id: u32) -> Result<()> {
+        for key in self
+            .scan_keys(
+                ScopedKey::quota_usage_range(kind, id),
+                TiFs::USAGE_SHARDS as u32,
+            )
+            .await?
+        {
+            self.delete(key).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn list_quotas(&self) -> Result<Vec<Quota>> {
+        let mut quotas = Vec::new();
+        for pair in self.scan_all(ScopedKey::quota_range()).await? {
+            quotas.push(
+                self.read_quota_usage(Quota::deserialize(pair.value())?)
+                    .await?,
+            );
+        }
+        Ok(quotas)
+    }
+
+    /// Set limits of a user, group or project, the usage is counted if it has no quota before.
+    pub async fn set_quota(
+        &mut self,
+        kind: QuotaKind,
+        id: u32,
+        bytes_limit: Option<u64>,
+        inodes_limit: Option<u64>,
+    ) -> Result<Quota> {
+        let mut quota = match self.read_quota(kind, id).await? {
+            Some(quota) => quota,
+            None => {
+                let quota = self.count_usage(kind, id).await?;
+                self.clear_quota_usage(kind, id).await?;
+                let usage = QuotaUsage::new(quota.bytes as i64, quota.inodes as i64);
+                self.put(ScopedKey::quota_usage(kind, id, 0), usage.serialize()?)
+                    .await?;
+                quota
+            }
+        };
+        quota.bytes_limit = bytes_limit;
+        quota.inodes_limit = inodes_limit;
+        let stored = Quota {
+            bytes: 0,
+            inodes: 0,
+            ..quota
+        };
+        self.put(ScopedKey::quota(kind, id), stored.serialize()?)
+            .await?;
+        Ok(quota)
+    }
+
+    pub async fn remove_quota(&mut self, kind: QuotaKind, id: u32) -> Result<()> {
+        self.delete(ScopedKey::quota(kind, id)).await?;
+        self.clear_quota_usage(kind, id).await
+    }
+
+    /// Count the usage of a user, group or project by scanning all inodes.
+    async fn count_usage(&self, kind: QuotaKind, id: u32) -> Result<Quota> {
+        let mut quota = Quota::new(kind, id);
+        for inode in self.read_all_inodes().await? {
+            if QuotaKind::of(&inode).contains(&(kind, id)) {
+                quota.bytes += Self::quota_bytes(&inode) as u64;
+                quota.inodes += 1;
+            }
+        }
+        Ok(quota)
+    }
+
+    /// Change the owner or group of an inode, moving its usage between quotas.
+    ///
+    /// The setuid bit and the setgid bit of an executable are cleared if a file changes hands.
+    pub async fn chown(
+        &mut self,
+        inode: &mut Inode,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<()> {
+        if uid.unwrap_or(inode.uid) == inode.uid && gid.unwrap_or(inode.gid) == inode.gid {
+            return Ok(());
+        }
+        let bytes = Self::quota_bytes(inode);
+        self.charge_quota(inode, -bytes, -1).await?;
+        inode.uid = uid.unwrap_or(inode.uid);
+        inode.gid = gid.unwrap_or(inode.gid);
+        if inode.kind != FileType::Directory {
+            inode.perm &= !Self::privileges(inode.perm);
+        }
+        self.charge_quota(inode, bytes, 1).await
+    }
+
+    /// Tag a directory tree with a project, moving its usage to the project quota.
+    /// New files in the tree inherit the project of their parent.
+    pub async fn set_project(&mut self, root: u64, project: u32) -> Result<()> {
+        let mut visited = HashSet::new();
+        let mut inodes = vec![root];
+        while let Some(ino) = inodes.pop() {
+            if !visited.insert(ino) {
+                continue;
+            }
+            let mut inode = self.read_inode(ino).await?;
+            let bytes = Self::quota_bytes(&inode);
+            self.charge_quota(&inode, -bytes, -1).await?;
+            inode.project = project;
+            self.charge_quota(&inode, bytes, 1).await?;
+            self.save_inode(&inode).await?;
+            if inode.kind == FileType::Directory {
+                inodes.extend(self.read_dir(ino).await?.into_iter().map(|item| item.ino));
+            }
+        }
+        Ok(())
+    }
+
+    fn block_index(key: &Key) -> u64 {
+        match ScopedKey::parse(key.into()) {
+            Ok(ScopedKey::Block { ino: _, block }) => block,
+            Ok(ScopedKey::BlockLink { ino: _, block }) => block,
+            Ok(ScopedKey::Staged { ino: _, block }) => block,
+            Ok(ScopedKey::Delta {
+                ino: _,
+                block,
+                seq: _,
+            }) => block,
+            _ => unreachable!("the keys from scanning should be always valid block keys"),
+        }
+    }
+
+    fn delta_index(key: &Key) -> (u64, u64) {
+        match ScopedKey::parse(key.into()) {
+            Ok(ScopedKey::Delta { ino: _, block, seq }) => (block, seq),
+            _ => unreachable!("the keys from scanning should be always valid delta keys"),
+        }
+    }
+
+    fn delta_limit(blocks: &Range<u64>) -> u32 {
+        (blocks.end - blocks.start)
+            .saturating_mul(TiFs::MAX_BLOCK_DELTAS)
+            .min(u32::MAX as u64) as u32
+    }
+
+    /// Read the deltas of blocks in the range, as `(block, seq, delta)` in the order of writing.
+    async fn read_deltas(
+        &self,
+        ino: u64,
+        blocks: Range<u64>,
+    ) -> Result<Vec<(u64, u64, BlockDelta)>> {
+        let limit = Self::delta_limit(&blocks);
+        self.scan(ScopedKey::delta_range(ino, blocks), limit)
+            .await?
+            .map(|pair| {
+                let (block, seq) = Self::delta_index(pair.key());
+                Ok((block, seq, BlockDelta::decode(pair.value())?))
+            })
+            .collect()
+    }
+
+    async fn clear_deltas(&mut self, ino: u64, blocks: Range<u64>) -> Result<()> {
+        let limit = Self::delta_limit(&blocks);
+        let keys: Vec<_> = self
+            .scan_keys(ScopedKey::delta_range(ino, blocks), limit)
+            .await?
+            .collect();
+        for key in keys {
+            self.delete(key).await?;
+        }
+        Ok(())
+    }
+
+    /// Take a block with its deltas applied and deleted, return `None` if there is no delta.
+    ///
+    /// A delta is written after finding its block stored, which conflicts with neither a
+    /// truncation nor a hole punched at the same time. So deltas of a block which is not stored
+    /// are taken as written before the block is removed, and they are deleted without applying.
+    async fn fold_deltas(&mut self, inode: &Inode, block: u64) -> Result<Option<Vec<u8>>> {
+        let deltas = self.read_deltas(inode.ino, block..block + 1).await?;
+        if deltas.is_empty() {
+            return Ok(None);
+        }
+        let mut value = if inode.cow {
+            self.detach_block(inode.ino, block).await?
+        } else {
+            self.get(ScopedKey::block(inode.ino, block)).await?
+        };
+        for (_, seq, delta) in deltas {
+            if let Some(value) = value.as_mut() {
+                delta.apply(value);
+            }
+            self.delete(ScopedKey::delta(inode.ino, block, seq)).await?;
+        }
+        Ok(value)
+    }
+
+    async fn clear_blocks(&mut self, inode: &Inode) -> Result<()> {
+        let end_block = (inode.size + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
+        for block in 0..end_block {
+            if inode.cow {
+                self.detach_block(inode.ino, block).await?;
+            }
+            self.delete(ScopedKey::block(inode.ino, block)).await?;
+        }
+        self.clear_deltas(inode.ino, 0..end_block).await
+    }
+
+    /// Read a block which is going to be overwritten, detaching it from snapshots first.
+    ///
+    /// The deltas of the block are folded into the returned data, which is padded to a full block.
+    async fn take_block(&mut self, inode: &Inode, block: u64) -> Result<Option<Vec<u8>>> {
+        let value = match self.fold_deltas(inode, block).await? {
+            Some(value) => Some(value),
+            None if inode.cow => self.detach_block(inode.ino, block).await?,
+            None => self.get(ScopedKey::block(inode.ino, block)).await?,
+        };
+        Ok(value.map(|mut value| {
+            value.resize(TiFs::BLOCK_SIZE as usize, 0);
+            value
+        }))
+    }
+
+    /// Store a block without its trailing zeros, or delete it if it is all zero.
+    ///
+    /// Return the change of allocated blocks, given whether the block was allocated before.
+    /// A shared block must be detached before it is stored.
+    async fn put_block(
+        &mut self,
+        ino: u64,
+        block: u64,
+        mut value: Vec<u8>,
+        allocated: bool,
+    ) -> Result<i64> {
+        let len = value
+            .iter()
+            .rposition(|byte| *byte != 0)
+            .map_or(0, |pos| pos + 1);
+        if len == 0 {
+            self.delete(ScopedKey::block(ino, block)).await?;
+            return Ok(-(allocated as i64));
+        }
+        value.truncate(len);
+        self.put(ScopedKey::block(ino, block), value).await?;
+        Ok((!allocated) as i64)
+    }
+
+    /// Indexes of blocks in the range which are stored for the inode, owned or linked.
+    async fn allocated_blocks(&self, inode: &Inode, blocks: Range<u64>) -> Result<HashSet<u64>> {
+        let limit = (blocks.end - blocks.start).min(u32::MAX as u64) as u32;
+        let mut allocated: HashSet<_> = self
+            .scan_keys(ScopedKey::block_range(inode.ino, blocks.clone()), limit)
+            .await?
+            .map(|key| Self::block_index(&key))
+            .collect();
+        if inode.cow {
+            allocated.extend(
+                self.scan_keys(ScopedKey::block_link_range(inode.ino, blocks), limit)
+                    .await?
+                    .map(|key| Self::block_index(&key)),
+            );
+        }
+        Ok(allocated)
+    }
+
+    async fn read_block_share(&self, ino: u64, block: u64) -> Result<BlockShare> {
+        self.get(ScopedKey::block_share(ino, block))
+            .await?
+            .map(|data| BlockShare::deserialize(&data))
+            .unwrap_or_else(|| Ok(BlockShare::default()))
+    }
+
+    async fn save_block_share(&mut self, ino: u64, block: u64, share: &BlockShare) -> Result<()> {
+        let key = ScopedKey::block_share(ino, block);
+        if share.sharers.is_empty() {
+            self.delete(key).await?;
+        } else {
+            self.put(key, share.serialize()?).await?;
+        }
+        Ok(())
+    }
+
+    /// Link a block of `dst` to the data of the same block of `owner`.
+    async fn share_block(&mut self, owner: u64, dst: u64, block: u64) -> Result<()> {
+        self.put(
+            ScopedKey::block_link(dst, block),
+            BlockLink::new(owner).serialize()?,
+        )
+        .await?;
+        let mut share = self.read_block_share(owner, block).await?;
+        share.sharers.push(dst);
+        self.save_block_share(owner, block, &share).await
+    }
+
+    /// Share a batch of blocks of `lender` with `borrower` from the end, and clear the intents
+    /// of both inodes once all blocks are shared. Return true if nothing is left.
+    async fn step_share(&mut self, borrower: u64, lender: u64) -> Result<bool> {
+        let mut dst = self.read_inode_unchecked(borrower).await?;
+        let mut src = self.read_inode_unchecked(lender).await?;
+        let mut intent = match dst.intent.take() {
+            Some(intent) if intent.kind == IntentKind::Share && intent.target == lender => intent,
+            _ => {
+                warn!("inode({}) lends blocks to no inode", lender);
+                src.intent = None;
+                self.save_inode(&src).await?;
+                return Ok(true);
+            }
+        };
+
+        let from = intent.end_block.saturating_sub(TiFs::MAX_TXN_BLOCKS);
+        self.share_blocks(&src, borrower, from..intent.end_block)
+            .await?;
+        if from > 0 {
+            debug!("sharing {:?} of inode({}) is in progress", &intent, lender);
+            intent.end_block = from;
+            dst.intent = Some(intent);
+            self.put_inode(&dst).await?;
+            return Ok(false);
+        }
+
+        debug!("sharing blocks of inode({}) is done", lender);
+        src.intent = None;
+        self.save_inode(&src).await?;
+        self.save_inode(&dst).await?;
+        Ok(true)
+    }
+
+    /// Share blocks in the range of `src` with `dst`, folding deltas of `src` first.
+    async fn share_blocks(&mut self, src: &Inode, dst: u64, blocks: Range<u64>) -> Result<()> {
+        let limit = (blocks.end - blocks.start) as u32;
+        let mut folded: Vec<_> = self
+            .read_deltas(src.ino, blocks.clone())
+            .await?
+            .into_iter()
+            .map(|(block, _, _)| block)
+            .collect();
+        folded.dedup();
+        for block in folded {
+            if let Some(value) = self.fold_deltas(src, block).await? {
+                self.put(ScopedKey::block(src.ino, block), value).await?;
+            }
+        }
+
+        let owned: Vec<_> = self
+            .scan_keys(ScopedKey::block_range(src.ino, blocks.clone()), limit)
+            .await?
+            .collect();
+        for key in owned {
+            self.share_block(src.ino, dst, Self::block_index(&key))
+                .await?;
+        }
+
+        if src.cow {
+            let links: Vec<_> = self
+                .scan(ScopedKey::block_link_range(src.ino, blocks), limit)
+                .await?
+                .collect();
+            for pair in links {
+                let owner = BlockLink::deserialize(pair.value())?.owner;
+                self.share_block(owner, dst, Self::block_index(pair.key()))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Make a block exclusive to `ino` and return its current data.
+    ///
+    /// A linked block is unlinked, leaving the caller to store its own copy.
+    /// An owned block with sharers is handed over to the first sharer.
+    async fn detach_block(&mut self, ino: u64, block: u64) -> Result<Option<Vec<u8>>> {
+        if let Some(link) = self.get(ScopedKey::block_link(ino, block)).await? {
+            let owner = BlockLink::deserialize(&link)?.owner;
+            self.delete(ScopedKey::block_link(ino, block)).await?;
+            let mut share = self.read_block_share(owner, block).await?;
+            share.sharers.retain(|sharer| *sharer != ino);
+            self.save_block_share(owner, block, &share).await?;
+            return Ok(self.get(ScopedKey::block(owner, block)).await?);
+        }
+
+        let data = self.get(ScopedKey::block(ino, block)).await?;
+        let share = self.read_block_share(ino, block).await?;
+        if let (Some(value), Some((heir, rest))) = (&data, share.sharers.split_first()) {
+            debug!("hand over block(<{}>[{}]) to inode({})", ino, block, heir);
+            self.delete(ScopedKey::block_link(*heir, block)).await?;
+            self.put(ScopedKey::block(*heir, block), value.clone())
+                .await?;
+            for sharer in rest {
+                self.put(
+                    ScopedKey::block_link(*sharer, block),
+                    BlockLink::new(*heir).serialize()?,
+                )
+                .await?;
+            }
+            let rest = BlockShare {
+                sharers: rest.to_vec(),
+            };
+            self.save_block_share(*heir, block, &rest).await?;
+            self.delete(ScopedKey::block_share(ino, block)).await?;
+        }
+        Ok(data)
+    }
+
+    /// Queue a directory to be walked by the tree intent of `root`.
+    async fn queue_tree_task(&mut self, root: u64, dir: u64) -> Result<()> {
+        self.put(
+            ScopedKey::tree_task(root, dir),
+            TreeTask::default().serialize()?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Set a `Clone` or `Empty` intent on the directory `root`, which walks the tree under it
+    /// from the directory itself.
+    async fn set_tree_intent(
+        &mut self,
+        root: &mut Inode,
+        kind: IntentKind,
+        target: u64,
+    ) -> Result<()> {
+        if kind == IntentKind::Clone {
+            self.copy_listing(target, root).await?;
+        }
+        self.queue_tree_task(root.ino, root.ino).await?;
+        root.intent = Some(Intent::tree(kind, target, self.clock));
+        if !self.intents.contains(&root.ino) {
+            self.intents.push(root.ino);
+        }
+        Ok(())
+    }
+
+    /// Copy the entries of the directory `src` into `dst` as they are, to be replaced by
+    /// copies of their inodes batch by batch.
+    async fn copy_listing(&mut self, src: u64, dst: &mut Inode) -> Result<()> {
+        let source = self.read_inode(src).await?;
+        let data = super::dir::encode(&self.read_dir(src).await?)?;
+        dst.nlink = source.nlink;
+        dst.set_size(data.len() as u64);
+        self.put(ScopedKey::block(dst.ino, 0), data).await?;
+        Ok(())
+    }
+
+    /// Apply a bounded step of the `Clone` or `Empty` intent of a directory, return true if
+    /// nothing is left.
+    ///
+    /// Directories of the tree left to walk are queued in `TreeTask` keys of the root, and
+    /// each step copies or removes a batch of entries of the first one.
+    async fn step_tree(&mut self, ino: u64, intent: Intent) -> Result<bool> {
+        let task = self.scan(ScopedKey::tree_task_range(ino), 1).await?.next();
+        if let Some(pair) = task {
+            let dir = match ScopedKey::parse(pair.key().into())? {
+                ScopedKey::TreeTask { root: _, dir } => dir,
+                _ => unreachable!("the keys from scanning should be always valid tree task keys"),
+            };
+            let mut task = TreeTask::deserialize(pair.value())?;
+            let walked = if intent.kind == IntentKind::Clone {
+                self.clone_entries(ino, dir, &mut task).await?
+            } else {
+                self.remove_entries(ino, dir).await?
+            };
+            if walked {
+                self.delete(ScopedKey::tree_task(ino, dir)).await?;
+            } else {
+                self.put(ScopedKey::tree_task(ino, dir), task.serialize()?)
+                    .await?;
+            }
+            return Ok(false);
+        }
+
+        let forks: Vec<_> = self
+            .scan_keys(
+                ScopedKey::tree_fork_range(ino),
+                TiFs::MAX_TXN_ENTRIES as u32,
+            )
+            .await?
+            .collect();
+        if !forks.is_empty() {
+            for key in forks {
+                self.delete(key).await?;
+            }
+            return Ok(false);
+        }
+
+        debug!("intent {:?} of inode({}) is done", &intent, ino);
+        let mut root = self.read_inode_unchecked(ino).await?;
+        root.intent = None;
+        if intent.kind == IntentKind::Empty {
+            if root.nlink != 0 {
+                root.nlink = 2;
+            }
+            root.touch_mtime();
+            if intent.target != 0 {
+                self.set_tree_intent(&mut root, IntentKind::Clone, intent.target)
+                    .await?;
+                self.put_inode(&root).await?;
+                return Ok(false);
+            }
+        }
+        self.save_inode(&root).await?;
+        Ok(true)
+    }
+
+    /// Copy a batch of entries of the directory `dir` in the tree being cloned under `root`,
+    /// return true if all of them are copied.
+    ///
+    /// Entries not copied yet still name inodes of the source tree, and an entry whose inode
+    /// has been removed from the source meanwhile is dropped.
+    async fn clone_entries(&mut self, root: u64, dir: u64, task: &mut TreeTask) -> Result<bool> {
+        let readonly = self.read_inode_unchecked(root).await?.readonly;
+        let mut entries = self.read_dir(dir).await?;
+        let start = task.copied as usize;
+        let end = entries.len().min(start + TiFs::MAX_TXN_ENTRIES as usize);
+        let mut copied = Vec::with_capacity(end - start);
+        let mut dropped_dirs = 0;
+        for item in entries.drain(start..end).collect::<Vec<_>>() {
+            match self.clone_entry(root, dir, &item, readonly).await? {
+                Some(ino) => copied.push(DirItem { ino, ..item }),
+                None if item.typ == FileType::Directory => dropped_dirs += 1,
+                None => (),
+            }
+        }
+        task.copied += copied.len() as u64;
+        entries.splice(start..start, copied);
+
+        let data = super::dir::encode(&entries)?;
+        let mut inode = self.read_inode_unchecked(dir).await?;
+        inode.nlink -= dropped_dirs;
+        inode.set_size(data.len() as u64);
+        self.put(ScopedKey::block(dir, 0), data).await?;
+        self.put_inode(&inode).await?;
+        Ok(task.copied as usize == entries.len())
+    }
+
+    /// Copy an entry of a directory in the tree being cloned under `root` into the directory
+    /// `dir`, return the copy or `None` if the source is gone.
+    ///
+    /// Blocks of files are not shared here: each copied file gets a `Share` intent and its
+    /// source a `Lend` intent, which share the blocks in batches after this step.
+    async fn clone_entry(
+        &mut self,
+        root: u64,
+        dir: u64,
+        item: &DirItem,
+        readonly: bool,
+    ) -> Result<Option<u64>> {
+        if let Some(data) = self.get(ScopedKey::tree_fork(root, item.ino)).await? {
+            let mut inode = self
+                .read_inode_unchecked(TreeFork::deserialize(&data)?.ino)
+                .await?;
+            inode.nlink += 1;
+            self.put_inode(&inode).await?;
+            self.set_index(dir, item.name.clone().into(), inode.ino)
+                .await?;
+            return Ok(Some(inode.ino));
+        }
+        let mut src = match self.read_inode(item.ino).await {
+            Err(FsError::InodeNotFound { inode: _ }) => return Ok(None),
+            result => result?,
+        };
+
+        let mut inode = src.fork(self.alloc_ino().await?);
+        inode.readonly = readonly;
+        self.charge(
+            &inode,
+            Self::quota_bytes(&inode),
+            Self::usage_blocks(&inode),
+            1,
+        )
+        .await?;
+        self.copy_xattrs(src.ino, inode.ino).await?;
+        if src.kind == FileType::Directory {
+            self.copy_listing(src.ino, &mut inode).await?;
+            self.queue_tree_task(root, inode.ino).await?;
+            self.set_index(inode.ino, PARENT_NAME.into(), dir).await?;
+        } else {
+            inode.nlink = 1;
+            if src.nlink > 1 {
+                self.put(
+                    ScopedKey::tree_fork(root, src.ino),
+                    TreeFork::new(inode.ino).serialize()?,
+                )
+                .await?;
+            }
+        }
+
+        let end_block = (src.size + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
+        if src.kind != FileType::Directory && src.inline_data.is_none() && end_block > 0 {
+            inode.intent = Some(Intent::share(
+                IntentKind::Share,
+                src.ino,
+                end_block,
+                self.clock,
+            ));
+            inode.cow = true;
+            self.intents.push(inode.ino);
+            src.intent = Some(Intent::share(
+                IntentKind::Lend,
+                inode.ino,
+                end_block,
+                self.clock,
+            ));
+            src.cow = true;
+            self.save_inode(&src).await?;
+        }
+        self.save_inode(&inode).await?;
+        self.set_index(dir, item.name.clone().into(), inode.ino)
+            .await?;
+        Ok(Some(inode.ino))
+    }
+
+    /// Remove a batch of entries of the directory `dir` in the tree being emptied under `root`
+    /// from the end, return true if none is left.
+    ///
+    /// A subdirectory is queued to be emptied, and reclaimed once it is empty.
+    async fn remove_entries(&mut self, root: u64, dir: u64) -> Result<bool> {
+        let mut entries = self.read_dir(dir).await?;
+        let start = entries.len().saturating_sub(TiFs::MAX_TXN_ENTRIES as usize);
+        for item in entries.drain(start..).collect::<Vec<_>>() {
+            self.remove_index(dir, item.name.clone().into()).await?;
+            let mut inode = match self.read_inode(item.ino).await {
+                Err(FsError::InodeNotFound { inode: _ }) => continue,
+                result => result?,
+            };
+            if inode.kind == FileType::Directory {
+                self.queue_tree_task(root, inode.ino).await?;
+            } else {
+                inode.nlink -= 1;
+                inode.ctime = SystemTime::now();
+                self.save_inode(&inode).await?;
+            }
+        }
+
+        let data = super::dir::encode(&entries)?;
+        let mut inode = self.read_inode_unchecked(dir).await?;
+        inode.set_size(data.len() as u64);
+        self.put(ScopedKey::block(dir, 0), data).await?;
+        if entries.is_empty() && dir != root {
+            self.remove_index(dir, PARENT_NAME.into()).await?;
+            inode.nlink = 0;
+            self.save_inode(&inode).await?;
+        } else {
+            self.put_inode(&inode).await?;
+        }
+        Ok(entries.is_empty())
+    }
+
+    pub async fn read_snapshot(&self, name: ByteString) -> Result<Snapshot> {
+        let data =
+            self.get(ScopedKey::snapshot(&name))
+                .await?
+                .ok_or_else(|| FsError::FileNotFound {
+                    file: name.to_string(),
+                })?;
+        Snapshot::deserialize(&data)
+    }
+
+    pub async fn list_snapshots(&self) -> Result<Vec<Snapshot>> {
+        self.scan(ScopedKey::snapshot_range(), TiFs::SCAN_LIMIT)
+            .await?
+            .map(|pair| Snapshot::deserialize(pair.value()))
+            .collect()
+    }
+
+    /// Take a snapshot of the tree under `source`, which is copied by the `Clone` intent of
+    /// the snapshot root after committing.
+    pub async fn create_snapshot(&mut self, name: ByteString, source: u64) -> Result<Snapshot> {
+        if self.get(ScopedKey::snapshot(&name)).await?.is_some() {
+            return Err(FsError::FileExist {
+                file: name.to_string(),
+            });
+        }
+
+        let src = self.read_inode(source).await?;
+        if src.kind != FileType::Directory {
+            return Err(FsError::NotDirectory {
+                file: source.to_string(),
+            });
+        }
+
+        let mut root = src.fork(self.alloc_ino().await?);
+        root.readonly = true;
+        self.charge(&root, 0, 0, 1).await?;
+        self.copy_xattrs(source, root.ino).await?;
+        self.set_index(root.ino, PARENT_NAME.into(), SNAPSHOTS_INODE)
+            .await?;
+        self.set_tree_intent(&mut root, IntentKind::Clone, source)
+            .await?;
+        self.save_inode(&root).await?;
+        let snapshot = Snapshot::new(name.to_string(), source, root.ino);
+        self.put(ScopedKey::snapshot(&name), snapshot.serialize()?)
+            .await?;
+        debug!("create snapshot {:?}", &snapshot);
+        Ok(snapshot)
+    }
+
+    /// Restore the tree under the source of a snapshot, which is emptied and then copied from
+    /// the snapshot by the intent of the source after committing.
+    pub async fn restore_snapshot(&mut self, name: ByteString) -> Result<Snapshot> {
+        let snapshot = self.read_snapshot(name).await?;
+        // the snapshot is completed before it is copied
+        self.read_inode(snapshot.root).await?;
+        let mut target = self.read_inode(snapshot.source).await?;
+        self.set_tree_intent(&mut target, IntentKind::Empty, snapshot.root)
+            .await?;
+        self.save_inode(&target).await?;
+        debug!("restore snapshot {:?}", &snapshot);
+        Ok(snapshot)
+    }
+
+    /// Delete a snapshot, whose tree is removed by the `Empty` intent of its root after
+    /// committing.
+    pub async fn delete_snapshot(&mut self, name: ByteString) -> Result<()> {
+        let snapshot = self.read_snapshot(name.clone()).await?;
+        let mut root = self.read_inode(snapshot.root).await?;
+        self.remove_index(root.ino, PARENT_NAME.into()).await?;
+        root.nlink = 0;
+        self.set_tree_intent(&mut root, IntentKind::Empty, 0)
+            .await?;
+        self.put_inode(&root).await?;
+        self.delete(ScopedKey::snapshot(&name)).await?;
+        debug!("delete snapshot {:?}", &snapshot);
+        Ok(())
+    }
+}
+
+/// Operations of the underlying transaction or snapshot. Reads return collected pairs,
+/// and a read-only transaction is never written, since read-only closures only borrow it.
+impl Txn {
+    pub async fn get(&self, key: impl Into<Key>) -> Result<Option<Value>> {
+        Ok(match &self.txn {
+            Inner::Optimistic(txn) => txn.get(key).await?,
+            Inner::ReadOnly(snapshot) => snapshot.get(key).await?,
+        })
+    }
+
+    pub async fn batch_get(
+        &self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Result<impl Iterator<Item = KvPair>> {
+        let pairs: Vec<_> = match &self.txn {
+            Inner::Optimistic(txn) => txn.batch_get(keys).await?.collect(),
+            Inner::ReadOnly(snapshot) => snapshot.batch_get(keys).await?.collect(),
+        };
+        Ok(pairs.into_iter())
+    }
+
+    pub async fn scan(
+        &self,
+        range: impl Into<BoundRange>,
+        limit: u32,
+    ) -> Result<impl Iterator<Item = KvPair>> {
+        let pairs: Vec<_> = match &self.txn {
+            Inner::Optimistic(txn) => txn.scan(range, limit).await?.collect(),
+            Inner::ReadOnly(snapshot) => snapshot.scan(range, limit).await?.collect(),
+        };
+        Ok(pairs.into_iter())
+    }
+
+    pub async fn scan_keys(
+        &self,
+        range: impl Into<BoundRange>,
+        limit: u32,
+    ) -> Result<impl Iterator<Item = Key>> {
+        let keys: Vec<_> = match &self.txn {
+            Inner::Optimistic(txn) => txn.scan_keys(range, limit).await?.collect(),
+            Inner::ReadOnly(snapshot) => snapshot.scan_keys(range, limit).await?.collect(),
+        };
+        Ok(keys.into_iter())
+    }
+
+    /// Scan all pairs in the range, in pages of at most `TiFs::SCAN_LIMIT` pairs.
+    pub async fn scan_all(&self, range: Range<Key>) -> Result<Vec<KvPair>> {
+        let mut pairs = Vec::new();
+        let mut start = range.start;
+        loop {
+            let page: Vec<_> = self
+                .scan(start..range.end.clone(), TiFs::SCAN_LIMIT)
+                .await?
+                .collect();
+            let next = match page.last() {
+                Some(last) if page.len() == TiFs::SCAN_LIMIT as usize => {
+                    Self::key_after(last.key())
+                }
+                _ => {
+                    pairs.extend(page);
+                    return Ok(pairs);
+                }
+            };
+            pairs.extend(page);
+            start = next;
+        }
+    }
+
+    /// Scan all keys in the range, in pages of at most `TiFs::SCAN_LIMIT` keys.
+    pub async fn scan_keys_all(&self, range: Range<Key>) -> Result<Vec<Key>> {
+        let mut keys = Vec::new();
+        let mut start = range.start;
+        loop {
+            let page: Vec<_> = self
+                .scan_keys(start..range.end.clone(), TiFs::SCAN_LIMIT)
+                .await?
+                .collect();
+            let next = match page.last() {
+                Some(last) if page.len() == TiFs::SCAN_LIMIT as usize => Self::key_after(last),
+                _ => {
+                    keys.extend(page);
+                    return Ok(keys);
+                }
+            };
+            keys.extend(page);
+            start = next;
+        }
+    }
+
+    /// The smallest key greater than `key`.
+    fn key_after(key: &Key) -> Key {
+        let mut next: Vec<u8> = key.clone().into();
+        next.push(0);
+        next.into()
+    }
+
+    fn writable(&mut self) -> &mut Transaction {
+        match &mut self.txn {
+            Inner::Optimistic(txn) => txn,
+            Inner::ReadOnly(_) => unreachable!("a read-only transaction is never written"),
+        }
+    }
+
+    pub async fn put(&mut self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        Ok(self.writable().put(key, value).await?)
+    }
+
+    pub async fn delete(&mut self, key: impl Into<Key>) -> Result<()> {
+        Ok(self.writable().delete(key).await?)
+    }
+
+    pub async fn commit(&mut self) -> Result<()> {
+        match &mut self.txn {
+            Inner::Optimistic(txn) => Ok(txn.commit().await?),
+            Inner::ReadOnly(_) => Ok(()),
+        }
+    }
+
+    pub async fn rollback(&mut self) -> Result<()> {
+        match &mut self.txn {
+            Inner::Optimistic(txn) => Ok(txn.rollback().await?),
+            Inner::ReadOnly(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::var;
+
+    use super::*;
+
+    // tests against TiKV are ignored by default, run them with the PD endpoints
+    // separated by commas in `TIFS_TEST_PD`
+    async fn client() -> TransactionClient {
+        let endpoints = var("TIFS_TEST_PD").expect("TIFS_TEST_PD is not set");
+        TransactionClient::new_with_config(
+            endpoints.split(',').map(String::from).collect(),
+            Default::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Make a file linked once and opened by `sessions`, numbered by the clock so that
+    /// tests never share files.
+    async fn make_file(client: &TransactionClient, sessions: &[u64]) -> u64 {
+        let ino = Txn::clock(client).await.unwrap() << 16;
+        let inode = Inode::from(FileAttr {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::now(),
+            mtime: SystemTime::now(),
+            ctime: SystemTime::now(),
+            crtime: SystemTime::now(),
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: TiFs::BLOCK_SIZE as u32,
+            padding: 0,
+            flags: 0,
+        });
+        let mut txn = Txn::begin_optimistic(client).await.unwrap();
+        txn.save_inode(&inode).await.unwrap();
+        for session in sessions {
+            txn.open(ino, *session).await.unwrap();
+        }
+        txn.commit().await.unwrap();
+        ino
+    }
+
+    /// Drop a link of the file, like unlinking it from its directory.
+    async fn unlink(txn: &mut Txn, ino: u64) -> Result<()> {
+        let mut inode = txn.read_inode(ino).await?;
+        inode.nlink -= 1;
+        txn.save_inode(&inode).await
+    }
+
+    async fn exists(client: &TransactionClient, ino: u64) -> bool {
+        let txn = Txn::begin_snapshot(client).await.unwrap();
+        match txn.read_inode(ino).await {
+            Ok(_) => true,
+            Err(FsError::InodeNotFound { inode: _ }) => false,
+            Err(err) => panic!("fail to read inode({}): {}", ino, err),
+        }
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn close_conflicts_with_unlink() {
+        let client = client().await;
+        let ino = make_file(&client, &[1]).await;
+
+        let mut close = Txn::begin_optimistic(&client).await.unwrap();
+        let mut unlinking = Txn::begin_optimistic(&client).await.unwrap();
+        close.close(ino, 1).await.unwrap();
+        // the unlink still finds the file opened, and would leave it to the close
+        unlink(&mut unlinking, ino).await.unwrap();
+        close.commit().await.unwrap();
+        assert!(unlinking.commit().await.is_err());
+
+        let mut retry = Txn::begin_optimistic(&client).await.unwrap();
+        unlink(&mut retry, ino).await.unwrap();
+        retry.commit().await.unwrap();
+        assert!(!exists(&client, ino).await);
     }
-}
 
-impl DerefMut for Txn {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+    #[async_std::test]
+    #[ignore]
+    async fn concurrent_closes_conflict() {
+        let client = client().await;
+        let ino = make_file(&client, &[1, 2]).await;
+        let mut txn = Txn::begin_optimistic(&client).await.unwrap();
+        unlink(&mut txn, ino).await.unwrap();
+        txn.commit().await.unwrap();
+
+        // each close finds the file still opened by the other, and changes nothing of it
+        let mut first = Txn::begin_optimistic(&client).await.unwrap();
+        let mut second = Txn::begin_optimistic(&client).await.unwrap();
+        first.close(ino, 1).await.unwrap();
+        second.close(ino, 2).await.unwrap();
+        first.commit().await.unwrap();
+        assert!(second.commit().await.is_err());
+        assert!(exists(&client, ino).await);
+
+        let mut retry = Txn::begin_optimistic(&client).await.unwrap();
+        retry.close(ino, 2).await.unwrap();
+        retry.commit().await.unwrap();
+        assert!(!exists(&client, ino).await);
     }
 }
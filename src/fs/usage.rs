@@ -0,0 +1,68 @@
+use std::ops::AddAssign;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::{FsError, Result};
+use super::serialize::{deserialize, serialize, ENCODING};
+
+/// A shard of the usage counters of the volume.
+///
+/// A shard may be negative if files are freed through a shard other than the one they
+/// were counted in, only the sum of all shards is meaningful.
+#[derive(Debug, Default, Eq, PartialEq, Hash, Clone, Copy, Deserialize, Serialize)]
+pub struct Usage {
+    pub blocks: i64,
+    pub files: i64,
+}
+
+impl Usage {
+    pub const fn new(blocks: i64, files: i64) -> Self {
+        Self { blocks, files }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "usage",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "usage",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.blocks += other.blocks;
+        self.files += other.files;
+    }
+}
+
+/// The capacity and used blocks of the volume cached by a mount.
+///
+/// It is refreshed periodically and counts the blocks allocated by the mount since,
+/// so allocations of other mounts may exceed the capacity until the next refresh.
+#[derive(Debug, Default, Eq, PartialEq, Hash, Clone, Copy)]
+pub struct Space {
+    pub capacity: Option<u64>,
+    pub blocks: i64,
+}
+
+impl Space {
+    pub const fn new(capacity: Option<u64>, blocks: i64) -> Self {
+        Self { capacity, blocks }
+    }
+
+    /// Whether allocating more blocks would exceed the capacity.
+    pub fn exceeds(&self, blocks: i64, block_size: u64) -> bool {
+        self.capacity.map_or(false, |capacity| {
+            (self.blocks + blocks).max(0) as u64 * block_size > capacity
+        })
+    }
+}
@@ -63,7 +63,7 @@ macro_rules! define_options {
     };
 }
 
-define_options! { MountOption, [DirectIO], [
+define_options! { MountOption, [DirectIO, Noatime, Relatime, Strictatime], [
     Dev,
     NoDev,
     Suid,